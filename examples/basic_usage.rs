@@ -17,6 +17,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let config = FastVLMConfig {
         max_response_length: 50,
         default_prompt: "用中文描述这张图片的内容".to_string(),
+        ..Default::default()
     };
     println!("✅ 配置设置完成");
     
@@ -15,6 +15,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let config = FastVLMConfig {
         max_response_length: 30,
         default_prompt: "简要描述这张图片".to_string(),
+        ..Default::default()
     };
     
     println!("🔧 正在初始化模型...");
@@ -2,7 +2,7 @@
 //! 
 //! 这个示例展示了如何使用不同的配置选项来定制 FastVLM 的行为。
 
-use fastvlm::{FastVLMClient, FastVLMConfig};
+use fastvlm::{FastVLMClient, FastVLMConfig, FastVLMManifest};
 use std::error::Error;
 
 #[tokio::main]
@@ -17,18 +17,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
         ("简短描述", FastVLMConfig {
             max_response_length: 20,
             default_prompt: "用一句话描述这张图片".to_string(),
+            ..Default::default()
         }),
         ("详细描述", FastVLMConfig {
             max_response_length: 100,
             default_prompt: "详细描述这张图片的内容、场景、颜色和细节".to_string(),
+            ..Default::default()
         }),
         ("情感分析", FastVLMConfig {
             max_response_length: 50,
             default_prompt: "分析这张图片传达的情感和氛围".to_string(),
+            ..Default::default()
         }),
         ("物体识别", FastVLMConfig {
             max_response_length: 40,
             default_prompt: "识别这张图片中的主要物体和元素".to_string(),
+            ..Default::default()
         }),
     ];
     
@@ -108,16 +112,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let default_model_dir = fastvlm::get_default_model_dir();
     println!("默认模型目录: {}", default_model_dir.display());
     
-    // 检查模型文件是否存在
-    let model_files = vec![
-        "tokenizer.json",
-        "vision_encoder.onnx",
-        "embed_tokens.onnx",
-        "decoder_model_merged.onnx",
-    ];
+    // 按模型目录清单检查模型文件是否存在
+    let manifest = FastVLMManifest::resolve(&default_model_dir, None)?;
     
-    println!("模型文件检查:");
-    for file in model_files {
+    println!("模型文件检查 ({}):", manifest.variant);
+    for file in &manifest.files {
         let file_path = default_model_dir.join(file);
         if file_path.exists() {
             println!("   ✅ {} - 存在", file);
@@ -16,6 +16,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let config = FastVLMConfig {
         max_response_length: 50,
         default_prompt: "描述这张图片".to_string(),
+        ..Default::default()
     };
     
     // 初始化模型（使用 data/fastvlm 目录）
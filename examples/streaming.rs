@@ -0,0 +1,53 @@
+//! 流式输出示例
+//! 
+//! 这个示例展示了如何在生成过程中逐段输出文本。
+
+use fastvlm::{FastVLMClient, FastVLMConfig, FastVLMRequestOptions, FastVLMStreamEvent};
+use futures_util::StreamExt;
+use std::error::Error;
+use std::io::Write;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    println!("🚀 FastVLM 流式输出示例");
+    
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        println!("💡 提示: 运行此示例时需要提供图片路径作为参数");
+        println!("   例如: cargo run --example streaming image.jpg");
+        return Ok(());
+    }
+    let image_path = &args[1];
+    
+    // 1. 初始化模型（使用 data/fastvlm 目录）
+    let mut client = FastVLMClient::new();
+    let config = FastVLMConfig {
+        max_response_length: 80,
+        default_prompt: "详细描述这张图片".to_string(),
+        ..Default::default()
+    };
+    client.initialize(Some("data/fastvlm"), config).await?;
+    
+    // 2. 逐段打印生成的文本
+    println!("📸 分析图片: {}", image_path);
+    let stream = client.analyze_image_file_stream(image_path, None, &FastVLMRequestOptions::default()).await?;
+    futures_util::pin_mut!(stream);
+    
+    while let Some(event) = stream.next().await {
+        match event? {
+            FastVLMStreamEvent::Delta(text) => {
+                print!("{}", text);
+                std::io::stdout().flush()?;
+            },
+            FastVLMStreamEvent::Done(result) => {
+                println!();
+                println!("✅ 分析完成 ({:?})", result.finish_reason);
+                println!("⏱️  处理时间: {:.2}秒", result.processing_time.as_secs_f32());
+                println!("🔢 Token 用量: 提示词 {} + 图像 {} + 生成 {}",
+                         result.usage.prompt_tokens, result.usage.image_tokens, result.usage.completion_tokens);
+            },
+        }
+    }
+    
+    Ok(())
+}
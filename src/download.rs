@@ -1,102 +1,391 @@
 use anyhow::{Context, Result};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tokio::io::AsyncWriteExt;
 use futures_util::StreamExt;
 use tracing::{info, error};
 
-/// FastVLM model file information
-struct ModelFile {
-    name: &'static str,
-    url: &'static str,
-    size_mb: f32,
-}
-
-const FASTVLM_MODELS: &[ModelFile] = &[
-    ModelFile {
-        name: "vision_encoder.onnx",
-        url: "https://huggingface.co/onnx-community/FastVLM-0.5B-ONNX/resolve/main/onnx/vision_encoder.onnx",
-        size_mb: 450.0,
-    },
-    ModelFile {
-        name: "embed_tokens.onnx", 
-        url: "https://huggingface.co/onnx-community/FastVLM-0.5B-ONNX/resolve/main/onnx/embed_tokens.onnx",
-        size_mb: 12.0,
-    },
-    ModelFile {
-        name: "decoder_model_merged.onnx",
-        url: "https://huggingface.co/onnx-community/FastVLM-0.5B-ONNX/resolve/main/onnx/decoder_model_merged.onnx", 
-        size_mb: 920.0,
-    },
-    ModelFile {
-        name: "tokenizer.json",
-        url: "https://huggingface.co/onnx-community/FastVLM-0.5B-ONNX/resolve/main/tokenizer.json",
-        size_mb: 2.2,
-    },
-];
-
-/// Download FastVLM models to the specified directory
-pub async fn download_fastvlm_models(model_dir: &Path) -> Result<()> {
-    println!("🚀 Starting FastVLM model download to: {}", model_dir.display());
-    info!("Starting FastVLM model download to: {}", model_dir.display());
-    
+/// Name of the manifest written to the model directory after a download
+pub const MANIFEST_FILE: &str = "fastvlm_manifest.json";
+
+/// Parameter count of a FastVLM release
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FastVLMModelSize {
+    #[default]
+    B0_5,
+    B1_5,
+    B7,
+}
+
+/// Weight precision of an ONNX export
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FastVLMDtype {
+    #[default]
+    Fp32,
+    /// float16 weights, inputs, outputs and KV cache, rejected when the decoder is loaded
+    Fp16,
+    /// 8-bit dynamic quantization
+    Q8,
+    /// 4-bit block quantization (MatMulNBits)
+    Q4,
+    /// 4-bit bitsandbytes quantization
+    Bnb4,
+}
+
+/// A downloadable FastVLM export, written as `<size>-<dtype>` (e.g. `0.5b-fp32`, `1.5b-q4`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct FastVLMVariant {
+    pub size: FastVLMModelSize,
+    pub dtype: FastVLMDtype,
+}
+
+/// ONNX graphs that make up a FastVLM export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastVLMComponent {
+    VisionEncoder,
+    EmbedTokens,
+    Decoder,
+}
+
+/// One file of a variant, as a path relative to the Hugging Face repository root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelFile {
+    pub repo_path: String,
+    /// Skipped when missing from the repository, e.g. `.onnx_data` external weights,
+    /// which only exist for exports over the 2 GB protobuf limit
+    pub optional: bool,
+}
+
+/// Files of a model directory, written by `download_fastvlm_models`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FastVLMManifest {
+    pub variant: FastVLMVariant,
+    pub repo: String,
+    pub vision_encoder: String,
+    pub embed_tokens: String,
+    pub decoder: String,
+    /// Every downloaded file, including `.onnx_data` external weights
+    pub files: Vec<String>,
+}
+
+impl FastVLMModelSize {
+    pub const ALL: [FastVLMModelSize; 3] = [Self::B0_5, Self::B1_5, Self::B7];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::B0_5 => "0.5b",
+            Self::B1_5 => "1.5b",
+            Self::B7 => "7b",
+        }
+    }
+
+    fn repo_name(&self) -> &'static str {
+        match self {
+            Self::B0_5 => "0.5B",
+            Self::B1_5 => "1.5B",
+            Self::B7 => "7B",
+        }
+    }
+}
+
+impl FastVLMDtype {
+    pub const ALL: [FastVLMDtype; 5] = [Self::Fp32, Self::Fp16, Self::Q8, Self::Q4, Self::Bnb4];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fp32 => "fp32",
+            Self::Fp16 => "fp16",
+            Self::Q8 => "q8",
+            Self::Q4 => "q4",
+            Self::Bnb4 => "bnb4",
+        }
+    }
+
+    /// File name suffix used by onnx-community exports
+    fn file_suffix(&self) -> &'static str {
+        match self {
+            Self::Fp32 => "",
+            Self::Fp16 => "_fp16",
+            Self::Q8 => "_quantized",
+            Self::Q4 => "_q4",
+            Self::Bnb4 => "_bnb4",
+        }
+    }
+}
+
+impl FastVLMComponent {
+    pub const ALL: [FastVLMComponent; 3] = [Self::VisionEncoder, Self::EmbedTokens, Self::Decoder];
+
+    fn file_stem(&self) -> &'static str {
+        match self {
+            Self::VisionEncoder => "vision_encoder",
+            Self::EmbedTokens => "embed_tokens",
+            Self::Decoder => "decoder_model_merged",
+        }
+    }
+}
+
+impl FastVLMVariant {
+    pub fn new(size: FastVLMModelSize, dtype: FastVLMDtype) -> Self {
+        Self { size, dtype }
+    }
+
+    /// Every known variant
+    pub fn all() -> Vec<FastVLMVariant> {
+        FastVLMModelSize::ALL.iter()
+            .flat_map(|&size| FastVLMDtype::ALL.iter().map(move |&dtype| Self::new(size, dtype)))
+            .collect()
+    }
+
+    /// Hugging Face repository holding this variant
+    pub fn repo(&self) -> String {
+        format!("onnx-community/FastVLM-{}-ONNX", self.size.repo_name())
+    }
+
+    /// Local file name of a component's ONNX graph
+    pub fn onnx_file(&self, component: FastVLMComponent) -> String {
+        format!("{}{}.onnx", component.file_stem(), self.dtype.file_suffix())
+    }
+
+    /// All files of this variant, in download order
+    pub fn files(&self) -> Vec<ModelFile> {
+        let mut files = Vec::new();
+        for component in FastVLMComponent::ALL {
+            let onnx_file = self.onnx_file(component);
+            files.push(ModelFile { repo_path: format!("onnx/{}_data", onnx_file), optional: true });
+            files.push(ModelFile { repo_path: format!("onnx/{}", onnx_file), optional: false });
+        }
+        for name in ["tokenizer.json", "config.json"] {
+            files.push(ModelFile { repo_path: name.to_string(), optional: false });
+        }
+        for name in ["generation_config.json", "preprocessor_config.json", "tokenizer_config.json"] {
+            files.push(ModelFile { repo_path: name.to_string(), optional: true });
+        }
+        files
+    }
+}
+
+impl ModelFile {
+    /// Files are stored flat in the model directory under their repository file name,
+    /// so `.onnx_data` files stay next to the graph that references them
+    pub fn name(&self) -> &str {
+        self.repo_path.rsplit('/').next().unwrap_or(&self.repo_path)
+    }
+
+    fn url(&self, repo: &str) -> String {
+        format!("https://huggingface.co/{}/resolve/main/{}", repo, self.repo_path)
+    }
+}
+
+impl fmt::Display for FastVLMVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.size.as_str(), self.dtype.as_str())
+    }
+}
+
+impl FromStr for FastVLMVariant {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let normalized = s.trim().to_ascii_lowercase();
+        let unknown = || {
+            let known: Vec<String> = Self::all().iter().map(|variant| variant.to_string()).collect();
+            anyhow::anyhow!("Unknown FastVLM variant '{}', expected one of: {}", s, known.join(", "))
+        };
+        let (size, dtype) = normalized.split_once('-').ok_or_else(unknown)?;
+        let size = FastVLMModelSize::ALL.into_iter().find(|candidate| candidate.as_str() == size).ok_or_else(unknown)?;
+        let dtype = FastVLMDtype::ALL.into_iter().find(|candidate| candidate.as_str() == dtype).ok_or_else(unknown)?;
+        Ok(Self::new(size, dtype))
+    }
+}
+
+impl From<FastVLMVariant> for String {
+    fn from(variant: FastVLMVariant) -> Self {
+        variant.to_string()
+    }
+}
+
+impl TryFrom<String> for FastVLMVariant {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl FastVLMManifest {
+    /// Manifest expected for `variant` when all of its required files are present
+    pub fn for_variant(variant: FastVLMVariant) -> Self {
+        Self {
+            variant,
+            repo: variant.repo(),
+            vision_encoder: variant.onnx_file(FastVLMComponent::VisionEncoder),
+            embed_tokens: variant.onnx_file(FastVLMComponent::EmbedTokens),
+            decoder: variant.onnx_file(FastVLMComponent::Decoder),
+            files: variant.files().iter()
+                .filter(|file| !file.optional)
+                .map(|file| file.name().to_string())
+                .collect(),
+        }
+    }
+
+    /// Read the manifest of a model directory, `None` if it has none
+    pub fn load(model_dir: &Path) -> Result<Option<Self>> {
+        let path = model_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let manifest = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid manifest {}", path.display()))?;
+        Ok(Some(manifest))
+    }
+
+    /// Files to load from `model_dir`
+    ///
+    /// Uses the directory's manifest, which must match `requested` when both are
+    /// given. Directories without a manifest are assumed to hold `requested`, or
+    /// the default `0.5b-fp32` variant.
+    pub fn resolve(model_dir: &Path, requested: Option<FastVLMVariant>) -> Result<Self> {
+        match (Self::load(model_dir)?, requested) {
+            (Some(manifest), Some(variant)) if manifest.variant != variant => Err(anyhow::anyhow!(
+                "Model directory {} holds FastVLM {} but {} was requested",
+                model_dir.display(), manifest.variant, variant
+            )),
+            (Some(manifest), _) => Ok(manifest),
+            (None, requested) => Ok(Self::for_variant(requested.unwrap_or_default())),
+        }
+    }
+
+    /// Record the files of `variant` already in `model_dir`, for directories filled without a manifest
+    ///
+    /// Returns `None` and writes nothing when a required file is missing.
+    pub fn adopt_existing(model_dir: &Path, variant: FastVLMVariant) -> Result<Option<Self>> {
+        let mut manifest = Self::for_variant(variant);
+        if !manifest.files_present(model_dir) {
+            return Ok(None);
+        }
+        manifest.files = variant.files().iter()
+            .map(|file| file.name().to_string())
+            .filter(|name| model_dir.join(name).exists())
+            .collect();
+        manifest.write(model_dir)?;
+        Ok(Some(manifest))
+    }
+
+    /// Whether every file listed in the manifest exists in `model_dir`
+    pub fn files_present(&self, model_dir: &Path) -> bool {
+        self.files.iter().all(|name| model_dir.join(name).exists())
+    }
+
+    fn write(&self, model_dir: &Path) -> Result<()> {
+        let path = model_dir.join(MANIFEST_FILE);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Download a FastVLM variant to the specified directory and record it in the manifest
+///
+/// Files already present are skipped. A directory can hold several dtypes of one
+/// model size, the manifest points at the last one downloaded.
+pub async fn download_fastvlm_models(model_dir: &Path, variant: FastVLMVariant) -> Result<FastVLMManifest> {
+    println!("🚀 Starting FastVLM {} download to: {}", variant, model_dir.display());
+    info!("Starting FastVLM {} download to: {}", variant, model_dir.display());
+
+    if let Some(existing) = FastVLMManifest::load(model_dir)? {
+        // tokenizer.json and config.json are shared by all dtypes of one size only
+        if existing.variant.size != variant.size {
+            return Err(anyhow::anyhow!(
+                "Model directory {} already holds FastVLM {}, use a separate directory for {}",
+                model_dir.display(), existing.variant, variant
+            ));
+        }
+    }
+
     fs::create_dir_all(model_dir)
         .with_context(|| format!("Failed to create model directory: {}", model_dir.display()))?;
 
     let client = Client::new();
-    let total_size: f32 = FASTVLM_MODELS.iter().map(|m| m.size_mb).sum();
-    
-    println!("📦 Total download size: {:.1} GB ({} files)", total_size / 1024.0, FASTVLM_MODELS.len());
-    info!("Total download size: {:.1} GB", total_size / 1024.0);
-
-    for (index, model) in FASTVLM_MODELS.iter().enumerate() {
-        let file_path = model_dir.join(model.name);
-        
+    let repo = variant.repo();
+    let files = variant.files();
+    let mut manifest = FastVLMManifest::for_variant(variant);
+    manifest.files.clear();
+    let mut total_bytes = 0u64;
+
+    println!("📦 {} files from {}", files.len(), repo);
+
+    for (index, model) in files.iter().enumerate() {
+        let file_path = model_dir.join(model.name());
+
         // Skip if file already exists
         if file_path.exists() {
-            println!("✅ Model {} already exists, skipping", model.name);
-            info!("Model {} already exists, skipping", model.name);
+            println!("✅ Model {} already exists, skipping", model.name());
+            info!("Model {} already exists, skipping", model.name());
+            total_bytes += fs::metadata(&file_path).map(|meta| meta.len()).unwrap_or(0);
+            manifest.files.push(model.name().to_string());
             continue;
         }
 
-        println!("📥 [{}/{}] Downloading {} ({:.1} MB)...", 
-                 index + 1, FASTVLM_MODELS.len(), model.name, model.size_mb);
-        info!("Downloading {} ({:.1} MB)...", model.name, model.size_mb);
-        
-        match download_file(&client, model.url, &file_path).await {
-            Ok(_) => {
-                println!("✅ [{}/{}] Successfully downloaded {}", 
-                         index + 1, FASTVLM_MODELS.len(), model.name);
-                info!("Successfully downloaded {}", model.name);
+        println!("📥 [{}/{}] Downloading {}...", index + 1, files.len(), model.name());
+        info!("Downloading {}...", model.name());
+
+        match download_file(&client, &model.url(&repo), &file_path).await {
+            Ok(Some(bytes)) => {
+                println!("✅ [{}/{}] Successfully downloaded {}",
+                         index + 1, files.len(), model.name());
+                info!("Successfully downloaded {}", model.name());
+                total_bytes += bytes;
+                manifest.files.push(model.name().to_string());
+            },
+            Ok(None) if model.optional => {
+                info!("{} is not part of {}, skipping", model.name(), variant);
+            },
+            Ok(None) => {
+                let e = anyhow::anyhow!("{} not found in {}", model.repo_path, repo);
+                println!("❌ Failed to download {}: {}", model.name(), e);
+                error!("Failed to download {}: {}", model.name(), e);
+                return Err(e);
             },
             Err(e) => {
-                println!("❌ Failed to download {}: {}", model.name, e);
-                error!("Failed to download {}: {}", model.name, e);
+                println!("❌ Failed to download {}: {}", model.name(), e);
+                error!("Failed to download {}: {}", model.name(), e);
                 return Err(e);
             }
         }
     }
 
+    manifest.write(model_dir)?;
+
     println!("🎉 FastVLM model download completed successfully!");
-    println!("");
+    println!();
     println!("📍 Models stored at: {}", model_dir.display());
-    println!("💾 Total space used: ~{:.1} GB", total_size / 1024.0);
-    println!("");
+    println!("💾 Total space used: ~{:.1} GB", total_bytes as f64 / (1024.0 * 1024.0 * 1024.0));
+    println!();
     println!("🗑️  To remove models later, delete this folder:");
     println!("   {}", model_dir.display());
-    println!("");
-    
+    println!();
+
     info!("FastVLM model download completed successfully");
-    Ok(())
+    Ok(manifest)
 }
 
-async fn download_file(client: &Client, url: &str, dest_path: &Path) -> Result<()> {
+/// Download `url` to `dest_path`, returning the size, or `None` if the file does not exist
+async fn download_file(client: &Client, url: &str, dest_path: &Path) -> Result<Option<u64>> {
     let response = client.get(url)
         .send()
         .await
         .with_context(|| format!("Failed to request {}", url))?;
 
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
     if !response.status().is_success() {
         return Err(anyhow::anyhow!("Download failed with status: {}", response.status()));
     }
@@ -104,29 +393,33 @@ async fn download_file(client: &Client, url: &str, dest_path: &Path) -> Result<(
     let total_size = response.content_length().unwrap_or(0);
     let mut downloaded = 0u64;
     let mut stream = response.bytes_stream();
-    
-    let mut file = tokio::fs::File::create(dest_path)
+
+    // Write to a temporary name so an interrupted download is not mistaken for a complete file
+    let mut partial_path = dest_path.as_os_str().to_owned();
+    partial_path.push(".part");
+    let partial_path = PathBuf::from(partial_path);
+    let mut file = tokio::fs::File::create(&partial_path)
         .await
-        .with_context(|| format!("Failed to create file: {}", dest_path.display()))?;
+        .with_context(|| format!("Failed to create file: {}", partial_path.display()))?;
 
     while let Some(chunk) = stream.next().await {
         let chunk = chunk
             .with_context(|| "Error reading download stream")?;
-        
+
         file.write_all(&chunk)
             .await
             .with_context(|| "Error writing to file")?;
-        
+
         downloaded += chunk.len() as u64;
-        
+
         if total_size > 0 && (downloaded % (10 * 1024 * 1024) == 0 || downloaded == total_size) {
             let progress = (downloaded as f64 / total_size as f64) * 100.0;
-            println!("   📊 Progress: {:.1}% ({:.1} MB / {:.1} MB)", 
-                     progress, 
+            println!("   📊 Progress: {:.1}% ({:.1} MB / {:.1} MB)",
+                     progress,
                      downloaded as f64 / (1024.0 * 1024.0),
                      total_size as f64 / (1024.0 * 1024.0));
-            info!("Download progress: {:.1}% ({:.1} MB / {:.1} MB)", 
-                  progress, 
+            info!("Download progress: {:.1}% ({:.1} MB / {:.1} MB)",
+                  progress,
                   downloaded as f64 / (1024.0 * 1024.0),
                   total_size as f64 / (1024.0 * 1024.0));
         }
@@ -134,8 +427,11 @@ async fn download_file(client: &Client, url: &str, dest_path: &Path) -> Result<(
 
     file.flush().await
         .with_context(|| "Failed to flush file")?;
+    drop(file);
+    tokio::fs::rename(&partial_path, dest_path).await
+        .with_context(|| format!("Failed to move {} into place", partial_path.display()))?;
 
-    Ok(())
+    Ok(Some(downloaded))
 }
 
 
@@ -158,7 +454,7 @@ pub fn get_default_model_dir() -> PathBuf {
             .map(|data| data.join("fastvlm").join("models"))
             .unwrap_or_else(|| PathBuf::from("data/fastvlm"))
     };
-    
+
     // Create the directory structure if it doesn't exist
     if let Err(e) = std::fs::create_dir_all(&model_dir) {
         info!("Could not create model directory {}, falling back to local: {}", model_dir.display(), e);
@@ -172,4 +468,78 @@ pub fn get_default_model_dir() -> PathBuf {
     } else {
         model_dir
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variant_identifiers_round_trip() {
+        for variant in FastVLMVariant::all() {
+            assert_eq!(variant.to_string().parse::<FastVLMVariant>().unwrap(), variant);
+        }
+        let variant: FastVLMVariant = " 1.5B-Q4 ".parse().unwrap();
+        assert_eq!(variant, FastVLMVariant::new(FastVLMModelSize::B1_5, FastVLMDtype::Q4));
+        assert!("0.5b".parse::<FastVLMVariant>().is_err());
+        assert!("3b-fp32".parse::<FastVLMVariant>().is_err());
+        assert_eq!(serde_json::to_string(&variant).unwrap(), "\"1.5b-q4\"");
+    }
+
+    #[test]
+    fn test_variant_files() {
+        let default = FastVLMManifest::for_variant(FastVLMVariant::default());
+        assert_eq!(default.repo, "onnx-community/FastVLM-0.5B-ONNX");
+        assert_eq!(default.decoder, "decoder_model_merged.onnx");
+        assert_eq!(default.files.len(), 5);
+        let fp16 = FastVLMManifest::for_variant("0.5b-fp16".parse().unwrap());
+        assert_eq!(fp16.decoder, "decoder_model_merged_fp16.onnx");
+
+        let variant: FastVLMVariant = "7b-q8".parse().unwrap();
+        let files = variant.files();
+        assert!(files.contains(&ModelFile { repo_path: "onnx/vision_encoder_quantized.onnx".to_string(), optional: false }));
+        assert!(files.contains(&ModelFile { repo_path: "onnx/decoder_model_merged_quantized.onnx_data".to_string(), optional: true }));
+        assert_eq!(files[1].name(), "vision_encoder_quantized.onnx");
+        assert_eq!(
+            ModelFile { repo_path: "tokenizer.json".to_string(), optional: false }.url(&variant.repo()),
+            "https://huggingface.co/onnx-community/FastVLM-7B-ONNX/resolve/main/tokenizer.json"
+        );
+    }
+
+    #[test]
+    fn test_resolve_manifest() {
+        let dir = std::env::temp_dir().join(format!("fastvlm-manifest-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let q4: FastVLMVariant = "0.5b-q4".parse().unwrap();
+
+        assert_eq!(FastVLMManifest::resolve(&dir, None).unwrap().variant, FastVLMVariant::default());
+        FastVLMManifest::for_variant(q4).write(&dir).unwrap();
+        assert_eq!(FastVLMManifest::resolve(&dir, None).unwrap().decoder, "decoder_model_merged_q4.onnx");
+        assert!(FastVLMManifest::resolve(&dir, Some(q4)).is_ok());
+        assert!(FastVLMManifest::resolve(&dir, Some(FastVLMVariant::default())).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_adopt_existing_files() {
+        let dir = std::env::temp_dir().join(format!("fastvlm-adopt-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let variant = FastVLMVariant::default();
+        let manifest = FastVLMManifest::for_variant(variant);
+
+        assert!(FastVLMManifest::adopt_existing(&dir, variant).unwrap().is_none());
+        for name in manifest.files.iter().chain(["generation_config.json".to_string()].iter()) {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let adopted = FastVLMManifest::adopt_existing(&dir, variant).unwrap().unwrap();
+        assert!(adopted.files.contains(&"generation_config.json".to_string()));
+        assert!(adopted.files_present(&dir));
+        assert_eq!(FastVLMManifest::load(&dir).unwrap(), Some(adopted));
+
+        fs::remove_file(dir.join("config.json")).unwrap();
+        assert!(!manifest.files_present(&dir));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,10 @@
 use anyhow::Result;
 use image::DynamicImage;
-use ndarray::{Array2, Array3, Array4, s};
+use ndarray::{Array1, Array2, Array3, s};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use ort::{
     session::Session,
     session::builder::GraphOptimizationLevel,
@@ -18,36 +22,270 @@ use ort::{
     execution_providers::CUDAExecutionProvider,
     execution_providers::CPUExecutionProvider,
 };
+use futures_util::Stream;
+use std::ops::ControlFlow;
 use std::path::Path;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokenizers::Tokenizer;
 
+use super::fastvlm_generation::{
+    find_stop_sequence, log_softmax, top_k_indices, BeamHypotheses, DecodingStrategy, FastVLMCandidate, FastVLMLogprobs, FastVLMUsage,
+    FinishReason, GenerationOutput, GenerationParams, GenerationState, KvCache, LoopDetection, PromptInput, StepLogprobs,
+    TokenAlternative, TokenLogprob,
+};
+use super::fastvlm_decoder_spec::DecoderSpec;
+use super::fastvlm_constraint::{ConstrainedLogitsProcessor, FastVLMConstraint, TokenVocabulary};
+use super::fastvlm_classify::{label_scores, FastVLMClassification};
 use super::fastvlm_image_process::FastVLMImageProcessor;
-
-// FastVLM special tokens
-const EOS_TOKEN_ID: i64 = 151645; // <|im_end|>
-const IM_END_TOKEN_ID: i64 = 151645; // <|im_end|>
-const IMAGE_TOKEN_ID: i64 = 151646; // <image>
+use super::fastvlm_logits::{LogitsProcessor, StoppingCriteria};
+use super::fastvlm_structured::{parse_json_answer, repair_prompt, schema_json, schema_prompt, structured_options, FastVLMTypedResult};
+use super::fastvlm_stream::{AnalysisStream, FastVLMStreamEvent};
+use super::fastvlm_sampling::{self, PenaltyParams, SamplingParams};
+use super::fastvlm_chat::{expand_image_placeholders, reusable_prefix_len, ChatSession, ModelFingerprint};
+use super::fastvlm_context::{fit_context, ContextFit, ContextOverflow, FastVLMTruncation, FittedPrompt};
+use super::fastvlm_cache::{image_hash, FastVLMCacheStats, LruCache, PixelKey, PrefixEntry, PrefixKey};
+use super::fastvlm_chat_template::{prompt_messages, ChatTemplate, FastVLMMessage, DEFAULT_SYSTEM_PROMPT};
+use super::fastvlm_model_config::{ModelConfigFiles, ModelDefaults, ENDOFTEXT_TOKEN_ID, IM_END_TOKEN_ID};
+use crate::download::{FastVLMManifest, FastVLMVariant};
 
 #[derive(Debug, Clone)]
 pub struct FastVLMAnalysisResult {
+    /// 生成的文本，没有生成任何内容时为空字符串，原因见 `finish_reason`
     pub text: String,
     pub timestamp: Instant,
     pub processing_time: Duration,
+    /// 生成结束的原因
+    pub finish_reason: FinishReason,
+    /// 每个生成 token 的对数概率及候选 token，仅在开启 `logprobs` 时返回
+    pub logprobs: Option<FastVLMLogprobs>,
+    /// 提示词、图像和生成内容的 token 数量，`completion_tokens` 为所有候选之和
+    pub usage: FastVLMUsage,
+    /// 全部候选结果，数量等于请求的 `n`，第一个候选即 `text` 等字段对应的结果
+    pub candidates: Vec<FastVLMCandidate>,
 }
 
 #[derive(Debug, Clone)]
 pub struct FastVLMConfig {
     pub max_response_length: usize,
     pub default_prompt: String,
+    /// 采样温度，0 表示贪心解码；None 时使用模型 `generation_config.json` 中的值，没有时为 0.7
+    pub temperature: Option<f32>,
+    /// 仅保留概率最高的 k 个 token，0 表示不限制；None 时使用模型的值，没有时为 50
+    pub top_k: Option<usize>,
+    /// 核采样阈值，1.0 表示不限制；None 时使用模型的值，没有时为 1.0
+    pub top_p: Option<f32>,
+    /// 丢弃概率低于 `min_p * 最大概率` 的 token，0 表示不限制
+    pub min_p: f32,
+    /// 随机种子，设置后相同的图片、提示和种子得到相同的输出
+    pub seed: Option<u64>,
+    /// 解码策略：采样、贪心或束搜索；None 时模型 `do_sample` 为 false 则贪心，否则采样
+    pub decoding_strategy: Option<DecodingStrategy>,
+    /// 重复惩罚（乘性），1.0 表示不惩罚；None 时使用模型的值，没有时为 1.0
+    pub repetition_penalty: Option<f32>,
+    /// 频率惩罚，按已生成次数线性扣减 logit
+    pub frequency_penalty: f32,
+    /// 存在惩罚，已生成过的 token 扣减一次 logit
+    pub presence_penalty: f32,
+    /// 禁止重复出现的 n-gram 大小，0 表示不限制
+    pub no_repeat_ngram_size: usize,
+    /// 循环检测，设置后发现重复的 n-gram 循环时提前结束生成
+    pub loop_detection: Option<LoopDetection>,
+    /// 结束 token 列表，None 时使用模型 `generation_config.json` 中的 `eos_token_id`，没有时为 `<|im_end|>` 和 `<|endoftext|>`
+    pub stop_token_ids: Option<Vec<u32>>,
+    /// 停止字符串，例如 "\n\n" 或 "."，匹配到的停止字符串不会出现在结果中
+    pub stop_sequences: Vec<String>,
+    /// 是否在结果中返回每个 token 的对数概率
+    pub logprobs: bool,
+    /// 开启 `logprobs` 时每个 token 额外返回的候选数量
+    pub top_logprobs: usize,
+    /// 自定义 logits 处理器，在惩罚之后、采样过滤之前按顺序执行
+    pub logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    /// 自定义停止条件，任意一个满足即结束生成
+    pub stopping_criteria: Vec<Arc<dyn StoppingCriteria>>,
+    /// 约束解码：生成的文本必须匹配正则、候选列表或语法
+    pub constraint: Option<FastVLMConstraint>,
+    /// `analyze_as` 解析 JSON 失败时的最大重试次数
+    pub max_parse_retries: usize,
+    /// 模型变体（大小和精度），None 时使用模型目录清单中记录的变体，没有清单则为 0.5b-fp32
+    pub model_variant: Option<FastVLMVariant>,
+    /// 系统提示词，None 时使用内置的默认提示词，空字符串表示不加系统消息
+    pub system_prompt: Option<String>,
+    /// 前缀缓存容量：按系统提示词和图片缓存预填充后的 KV 状态，对同一张图片的后续提问
+    /// 只需预填充问题部分，0 表示关闭
+    pub prefix_cache_size: usize,
+    /// 视觉特征缓存的最大条目数：按预处理后的像素和预处理参数缓存视觉编码器的输出，
+    /// 同一张图片再次分析时跳过视觉编码器，0 表示关闭
+    pub vision_cache_size: usize,
+    /// 视觉特征缓存的最大字节数
+    pub vision_cache_max_bytes: usize,
+    /// 上下文长度上限，None 时使用 config.json 中的 `max_position_embeddings`，两者都没有时不检查
+    pub max_context_length: Option<usize>,
+    /// 提示词加 `max_response_length` 超过上下文长度时的处理方式，默认返回错误
+    pub context_overflow: ContextOverflow,
 }
 
 impl Default for FastVLMConfig {
     fn default() -> Self {
+        let sampling = SamplingParams::default();
+        let penalties = PenaltyParams::default();
+        // 可由模型 generation_config.json 提供的字段默认为 None，加载模型时才确定
         Self {
             max_response_length: 30,
             default_prompt: "Describe this image briefly.".to_string(),
+            temperature: None,
+            top_k: None,
+            top_p: None,
+            min_p: sampling.min_p,
+            seed: None,
+            decoding_strategy: None,
+            repetition_penalty: None,
+            frequency_penalty: penalties.frequency_penalty,
+            presence_penalty: penalties.presence_penalty,
+            no_repeat_ngram_size: penalties.no_repeat_ngram_size,
+            loop_detection: None,
+            stop_token_ids: None,
+            stop_sequences: Vec::new(),
+            logprobs: false,
+            top_logprobs: 0,
+            logits_processors: Vec::new(),
+            stopping_criteria: Vec::new(),
+            constraint: None,
+            max_parse_retries: 2,
+            model_variant: None,
+            system_prompt: None,
+            prefix_cache_size: 4,
+            vision_cache_size: 16,
+            vision_cache_max_bytes: 256 * 1024 * 1024,
+            max_context_length: None,
+            context_overflow: ContextOverflow::default(),
+        }
+    }
+}
+
+/// 单次请求的选项，未设置的字段使用 `FastVLMConfig` 中的值
+#[derive(Debug, Clone, Default)]
+pub struct FastVLMRequestOptions {
+    pub max_response_length: Option<usize>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub seed: Option<u64>,
+    pub decoding_strategy: Option<DecodingStrategy>,
+    pub repetition_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub no_repeat_ngram_size: Option<usize>,
+    pub loop_detection: Option<LoopDetection>,
+    pub stop_token_ids: Option<Vec<u32>>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub logprobs: Option<bool>,
+    pub top_logprobs: Option<usize>,
+    /// 候选结果数量（默认 1），大于 1 时只编码一次图像和提示词，再独立采样多个回答
+    pub n: Option<usize>,
+    /// 本次请求额外的 logits 处理器，追加在配置中的处理器之后
+    pub logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    /// 本次请求额外的停止条件，与配置中的停止条件同时生效
+    pub stopping_criteria: Vec<Arc<dyn StoppingCriteria>>,
+    pub constraint: Option<FastVLMConstraint>,
+    pub max_parse_retries: Option<usize>,
+    pub system_prompt: Option<String>,
+    pub context_overflow: Option<ContextOverflow>,
+}
+
+/// 多图提示词的一部分，文本和图片可以任意交错
+#[derive(Debug, Clone)]
+pub enum FastVLMContentPart {
+    Text(String),
+    /// RGBA 字节数据及其宽高
+    Image { data: Vec<u8>, width: u32, height: u32 },
+}
+
+impl FastVLMContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text(text.into())
+    }
+
+    pub fn image(data: Vec<u8>, width: u32, height: u32) -> Self {
+        Self::Image { data, width, height }
+    }
+
+    /// 读取图片文件并转换为 RGBA
+    pub fn image_file(path: impl AsRef<Path>) -> Result<Self> {
+        let image = image::open(path.as_ref())
+            .map_err(|e| anyhow::anyhow!("Failed to open image {}: {}", path.as_ref().display(), e))?
+            .to_rgba8();
+        let (width, height) = image.dimensions();
+        Ok(Self::image(image.into_raw(), width, height))
+    }
+}
+
+impl FastVLMConfig {
+    /// 合并配置与请求选项，得到本次请求的采样参数
+    pub fn sampling_params(&self, options: &FastVLMRequestOptions) -> SamplingParams {
+        let builtin = SamplingParams::default();
+        SamplingParams {
+            temperature: options.temperature.or(self.temperature).unwrap_or(builtin.temperature),
+            top_k: options.top_k.or(self.top_k).unwrap_or(builtin.top_k),
+            top_p: options.top_p.or(self.top_p).unwrap_or(builtin.top_p),
+            min_p: options.min_p.unwrap_or(self.min_p),
+        }
+    }
+
+    /// 合并配置与请求选项，得到本次请求的惩罚参数
+    pub fn penalty_params(&self, options: &FastVLMRequestOptions) -> PenaltyParams {
+        PenaltyParams {
+            repetition_penalty: options.repetition_penalty.or(self.repetition_penalty)
+                .unwrap_or(PenaltyParams::default().repetition_penalty),
+            frequency_penalty: options.frequency_penalty.unwrap_or(self.frequency_penalty),
+            presence_penalty: options.presence_penalty.unwrap_or(self.presence_penalty),
+            no_repeat_ngram_size: options.no_repeat_ngram_size.unwrap_or(self.no_repeat_ngram_size),
+        }
+    }
+
+    /// 合并配置与请求选项，得到本次请求的系统提示词
+    pub fn system_prompt<'a>(&'a self, options: &'a FastVLMRequestOptions) -> &'a str {
+        options.system_prompt.as_deref()
+            .or(self.system_prompt.as_deref())
+            .unwrap_or(DEFAULT_SYSTEM_PROMPT)
+    }
+
+    /// 合并配置与请求选项，得到本次请求的生成参数
+    pub(crate) fn generation_params(&self, options: &FastVLMRequestOptions) -> Result<GenerationParams> {
+        let params = GenerationParams {
+            max_new_tokens: options.max_response_length.unwrap_or(self.max_response_length),
+            strategy: options.decoding_strategy.or(self.decoding_strategy).unwrap_or_default(),
+            sampling: self.sampling_params(options),
+            penalties: self.penalty_params(options),
+            loop_detection: options.loop_detection.or(self.loop_detection),
+            stop_token_ids: options.stop_token_ids.clone()
+                .or_else(|| self.stop_token_ids.clone())
+                .unwrap_or_else(|| vec![IM_END_TOKEN_ID, ENDOFTEXT_TOKEN_ID]),
+            stop_sequences: options.stop_sequences.clone().unwrap_or_else(|| self.stop_sequences.clone()),
+            logprobs: options.logprobs.unwrap_or(self.logprobs)
+                .then(|| options.top_logprobs.unwrap_or(self.top_logprobs)),
+            num_candidates: options.n.unwrap_or(1),
+            logits_processors: self.logits_processors.iter().chain(&options.logits_processors).cloned().collect(),
+            stopping_criteria: self.stopping_criteria.iter().chain(&options.stopping_criteria).cloned().collect(),
+            context_overflow: options.context_overflow.unwrap_or(self.context_overflow),
+        };
+        params.sampling.validate()?;
+        params.strategy.validate()?;
+        params.penalties.validate()?;
+        if let Some(loop_detection) = &params.loop_detection {
+            loop_detection.validate()?;
+        }
+        if params.stop_sequences.iter().any(|stop| stop.is_empty()) {
+            return Err(anyhow::anyhow!("stop_sequences must not contain empty strings"));
+        }
+        if params.num_candidates == 0 {
+            return Err(anyhow::anyhow!("n must be at least 1"));
+        }
+        if params.num_candidates > 1 && params.strategy != DecodingStrategy::Sample {
+            return Err(anyhow::anyhow!("n > 1 requires DecodingStrategy::Sample, got {:?}", params.strategy));
         }
+        Ok(params)
     }
 }
 
@@ -56,8 +294,22 @@ pub struct FastVLM {
     vision_encoder: Session,
     embed_tokens: Session,
     decoder: Session,
+    decoder_spec: DecoderSpec,
+    /// 从模型目录的配置文件读取的特殊 token 和生成默认值
+    model_defaults: ModelDefaults,
+    /// `tokenizer_config.json` 中的对话模板，没有时使用内置的 ChatML 模板
+    chat_template: ChatTemplate,
+    /// 模型标识，保存的会话只能由相同的模型加载
+    fingerprint: ModelFingerprint,
     config: FastVLMConfig,
     image_processor: FastVLMImageProcessor,
+    rng: StdRng,
+    /// 约束解码使用的 token 字节串，第一次使用时构建
+    vocabulary: Option<Arc<TokenVocabulary>>,
+    /// 系统提示词加图片的前缀 KV 状态，最近使用的保留
+    prefix_cache: LruCache<PrefixKey, PrefixEntry>,
+    /// 按像素内容缓存的视觉编码器输出
+    vision_cache: LruCache<PixelKey, Array3<f32>>,
 }
 
 impl FastVLM {
@@ -72,6 +324,10 @@ impl FastVLM {
                 tracing::debug!("ONNX Runtime already initialized or failed: {:?}", e);
             });
         
+        // 模型目录清单决定各组件的 ONNX 文件
+        let manifest = FastVLMManifest::resolve(data_dir, config.model_variant)?;
+        tracing::info!("Loading FastVLM {} from {}", manifest.variant, data_dir.display());
+
         let tokenizer_start = Instant::now();
         let tokenizer_path = data_dir.join("tokenizer.json");
         let tokenizer = Tokenizer::from_file(tokenizer_path)
@@ -121,9 +377,47 @@ impl FastVLM {
         };
         
         // Load models with individual timing
-        let vision_encoder = create_session("vision_encoder.onnx")?;
-        let embed_tokens = create_session("embed_tokens.onnx")?;
-        let decoder = create_session("decoder_model_merged.onnx")?;
+        let vision_encoder = create_session(&manifest.vision_encoder)?;
+        let embed_tokens = create_session(&manifest.embed_tokens)?;
+        let decoder = create_session(&manifest.decoder)?;
+        
+        // 从解码器的输入输出和 config.json 推断层数、KV 头数和 head_dim
+        let config_files = ModelConfigFiles::read(data_dir)?;
+        let decoder_spec = DecoderSpec::from_session(&decoder, config_files.config.as_ref())?;
+        tracing::info!("Decoder: {} layers, {} KV heads, head_dim {}",
+                      decoder_spec.num_layers, decoder_spec.num_kv_heads, decoder_spec.head_dim);
+        
+        // 特殊 token、图像预处理和采样参数优先使用模型自带的配置，内置值只作为后备
+        let model_defaults = ModelDefaults::resolve(
+            &config_files,
+            |token| tokenizer.token_to_id(token),
+            tokenizer.get_vocab_size(true),
+        );
+        tracing::info!("EOS tokens {:?}, image token {}", model_defaults.eos_token_ids, model_defaults.image_token_id);
+        let config = model_defaults.apply(config);
+        let chat_template = ChatTemplate::new(
+            config_files.chat_template(),
+            config_files.special_token("bos_token"),
+            config_files.special_token("eos_token"),
+        )?;
+        let fingerprint = ModelFingerprint {
+            variant: manifest.variant.to_string(),
+            file_sizes: [&manifest.vision_encoder, &manifest.embed_tokens, &manifest.decoder].iter()
+                .map(|file| {
+                    let file_size = |name: String| std::fs::metadata(data_dir.join(name)).map(|meta| meta.len()).unwrap_or(0);
+                    file_size(file.to_string()) + file_size(format!("{}_data", file))
+                })
+                .collect(),
+            num_layers: decoder_spec.num_layers,
+            num_kv_heads: decoder_spec.num_kv_heads,
+            head_dim: decoder_spec.head_dim,
+            vocab_size: model_defaults.vocab_size,
+            image_token_id: model_defaults.image_token_id,
+        };
+        let image_processor = match &config_files.preprocessor_config {
+            Some(preprocessor_config) => FastVLMImageProcessor::from_preprocessor_config(preprocessor_config),
+            None => FastVLMImageProcessor::new(),
+        };
         
         let total_init_time = init_start_time.elapsed();
         tracing::info!("FastVLM models loaded successfully in {:.2}ms", total_init_time.as_millis());
@@ -133,8 +427,19 @@ impl FastVLM {
             vision_encoder,
             embed_tokens,
             decoder,
+            decoder_spec,
+            rng: match config.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            model_defaults,
+            chat_template,
+            fingerprint,
+            prefix_cache: LruCache::new(config.prefix_cache_size),
+            vision_cache: LruCache::new(config.vision_cache_size).with_max_bytes(config.vision_cache_max_bytes),
             config,
-            image_processor: FastVLMImageProcessor::new(),
+            image_processor,
+            vocabulary: None,
         })
     }
     
@@ -146,8 +451,258 @@ impl FastVLM {
         height: u32,
         prompt: Option<String>,
     ) -> Result<FastVLMAnalysisResult> {
+        self.analyze_frame_with_options_sync(image_data, width, height, prompt, &FastVLMRequestOptions::default())
+    }
+
+    /// 同步分析图片，并使用单次请求的选项覆盖配置
+    pub fn analyze_frame_with_options_sync(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        if options.n.unwrap_or(1) <= 1 {
+            return self.analyze_frame_with_callback_sync(image_data, width, height, prompt, options, |_| ControlFlow::Continue(()));
+        }
+        
+        let start_time = Instant::now();
+        let (prompt_input, params, usage) = self.prepare_analysis(image_data, width, height, prompt, options)?;
+        
+        let generation_start = Instant::now();
+        let outputs = self.generate_candidates(prompt_input, params)?;
+        tracing::debug!("Generated {} candidates in {:.2}ms", outputs.len(), generation_start.elapsed().as_millis());
+        
+        Ok(self.finish_analysis(start_time, usage, outputs))
+    }
+
+    /// 同步分析图片，每生成一段新文本就调用一次 `on_delta`
+    /// 
+    /// `on_delta` 返回 `ControlFlow::Break(())` 时停止生成，结果的 `finish_reason` 为 `Cancelled`。
+    pub fn analyze_frame_with_callback_sync<F>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+        mut on_delta: F,
+    ) -> Result<FastVLMAnalysisResult>
+    where
+        F: FnMut(&str) -> ControlFlow<()>,
+    {
+        let start_time = Instant::now();
+        let (prompt_input, params, usage) = self.prepare_analysis(image_data, width, height, prompt, options)?;
+        if params.num_candidates > 1 {
+            return Err(anyhow::anyhow!("n > 1 is not supported for streaming output"));
+        }
+        
+        // 文本生成阶段
+        let generation_start = Instant::now();
+        let output = self.generate_with_decoder(prompt_input, params, &mut on_delta)?;
+        tracing::debug!("Text generation completed in {:.2}ms", generation_start.elapsed().as_millis());
+        
+        Ok(self.finish_analysis(start_time, usage, vec![output]))
+    }
+
+    /// 以 `Stream` 的形式分析图片
+    /// 
+    /// 图片编码和提示词预处理在调用时完成，之后每次轮询生成一个 token，依次产出
+    /// `FastVLMStreamEvent::Delta`，最后产出携带完整结果的 `FastVLMStreamEvent::Done`。
+    /// 提前丢弃 Stream 即可取消生成。
+    ///
+    /// 解码器在 `poll_next` 中同步运行，每次轮询会阻塞当前线程一次解码器前向（束搜索为整个
+    /// 生成过程）。在异步运行时中使用时，请在 `spawn_blocking` 或专用线程中消费 Stream。
+    pub fn analyze_frame_stream(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<impl Stream<Item = Result<FastVLMStreamEvent>> + '_> {
+        let start_time = Instant::now();
+        let (prompt_input, params, usage) = self.prepare_analysis(image_data, width, height, prompt, options)?;
+        if params.num_candidates > 1 {
+            return Err(anyhow::anyhow!("n > 1 is not supported for streaming output"));
+        }
+        Ok(futures_util::stream::iter(AnalysisStream::new(self, start_time, usage, prompt_input, params)))
+    }
+
+    /// 对同一张图片依次回答多个问题，每个问题返回一个结果
+    /// 
+    /// 图片只编码一次，系统提示词和图片部分的预填充也只做一次，之后每个问题只预填充
+    /// 自己的 token，与缓存设置无关。
+    pub fn analyze_frame_multi_sync<S: AsRef<str>>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompts: &[S],
+        options: &FastVLMRequestOptions,
+    ) -> Result<Vec<FastVLMAnalysisResult>> {
+        let image = self.rgba_to_dynamic_image(image_data, width, height)?;
+        let image_hash = image_hash(&image);
+        let system_prompt = self.config.system_prompt(options).to_string();
+        
+        if prompts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let prefix = self.image_prefix(&image, image_hash, &system_prompt)?;
+        prompts.iter().map(|prompt| {
+            let start_time = Instant::now();
+            let mut params = self.request_params(options)?;
+            let (prompt_input, usage) = self.prompt_after_prefix(&prefix, &image, prompt.as_ref(), &system_prompt, &mut params)?;
+            let outputs = self.generate_outputs(prompt_input, params)?;
+            Ok(self.finish_analysis(start_time, usage, outputs))
+        }).collect()
+    }
+
+    /// 异步回答关于同一张图片的多个问题，见 `analyze_frame_multi_sync`
+    pub async fn analyze_frame_multi<S: AsRef<str>>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompts: &[S],
+        options: &FastVLMRequestOptions,
+    ) -> Result<Vec<FastVLMAnalysisResult>> {
+        self.analyze_frame_multi_sync(image_data, width, height, prompts, options)
+    }
+
+    /// 分析由文本和多张图片交错组成的提示词，例如比较两张截图或并排的商品图
+    /// 
+    /// 每张图片单独编码，视觉特征替换它在提示词中的占位 token。图片部分在提示词中渲染为
+    /// 图像 token 加换行，文本部分原样拼接，整体作为一条用户消息。多图提示词不使用前缀缓存，
+    /// 视觉特征缓存仍然生效。
+    pub fn analyze_parts_sync(
+        &mut self,
+        parts: Vec<FastVLMContentPart>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        let start_time = Instant::now();
+        let mut params = self.request_params(options)?;
+        let system_prompt = self.config.system_prompt(options).to_string();
+        let (prompt_input, usage) = self.build_parts_input(parts, &system_prompt, &mut params)?;
+        let outputs = self.generate_outputs(prompt_input, params)?;
+        Ok(self.finish_analysis(start_time, usage, outputs))
+    }
+
+    /// 异步分析多图提示词，见 `analyze_parts_sync`
+    pub async fn analyze_parts(
+        &mut self,
+        parts: Vec<FastVLMContentPart>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        self.analyze_parts_sync(parts, options)
+    }
+
+    /// 不带图片的纯文本生成，例如汇总一批图片描述或把描述改写为标题
+    /// 
+    /// 跳过视觉编码器，直接把对话模板渲染后的消息交给解码器，采样、停止条件和约束解码与
+    /// 图片分析相同。`messages` 不以 system 消息开头时使用配置中的系统提示词。
+    pub fn generate_text_sync(
+        &mut self,
+        messages: &[FastVLMMessage],
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        let start_time = Instant::now();
+        let (prompt_input, params, usage) = self.prepare_text(messages, options)?;
+        let outputs = self.generate_outputs(prompt_input, params)?;
+        Ok(self.finish_analysis(start_time, usage, outputs))
+    }
+
+    /// 异步纯文本生成，见 `generate_text_sync`
+    pub async fn generate_text(
+        &mut self,
+        messages: &[FastVLMMessage],
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        self.generate_text_sync(messages, options)
+    }
+
+    /// 以 `Stream` 的形式进行纯文本生成，事件和阻塞行为与 `analyze_frame_stream` 相同
+    pub fn generate_text_stream(
+        &mut self,
+        messages: &[FastVLMMessage],
+        options: &FastVLMRequestOptions,
+    ) -> Result<impl Stream<Item = Result<FastVLMStreamEvent>> + '_> {
         let start_time = Instant::now();
+        let (prompt_input, params, usage) = self.prepare_text(messages, options)?;
+        if params.num_candidates > 1 {
+            return Err(anyhow::anyhow!("n > 1 is not supported for streaming output"));
+        }
+        Ok(futures_util::stream::iter(AnalysisStream::new(self, start_time, usage, prompt_input, params)))
+    }
+
+    /// 渲染纯文本对话并构建解码器的输入嵌入
+    fn prepare_text(
+        &mut self,
+        messages: &[FastVLMMessage],
+        options: &FastVLMRequestOptions,
+    ) -> Result<(PromptInput, GenerationParams, FastVLMUsage)> {
+        if messages.is_empty() {
+            return Err(anyhow::anyhow!("messages must not be empty"));
+        }
+        let mut params = self.request_params(options)?;
+        
+        let messages = if messages[0].role == "system" {
+            messages.to_vec()
+        } else {
+            prompt_messages(self.config.system_prompt(options), messages.iter().cloned())
+        };
+        // 超出上下文长度时截断最后一条用户消息
+        let last_user = messages.iter().rposition(|message| message.role == "user");
+        let user_text = last_user.map(|i| messages[i].content.clone()).unwrap_or_default();
+        let fitted = self.fit_prompt(&user_text, &mut params, |text| {
+            let mut messages = messages.clone();
+            if let Some(i) = last_user {
+                messages[i].content = text.to_string();
+            }
+            self.encode_prompt(self.chat_template.render(&messages, true)?, &[])
+        })?;
+        let prompt_ids = fitted.prompt_ids;
+        let input_embeds = self.prompt_embeds(&prompt_ids, &[], 0)?;
+        tracing::debug!("Text-only prompt: {} tokens", prompt_ids.len());
+        
+        let usage = FastVLMUsage::for_prompt(prompt_ids.len(), 0, fitted.truncation);
+        Ok((PromptInput { input_embeds, past: self.empty_kv_cache() }, params, usage))
+    }
+
+    /// 清空前缀缓存，例如切换到新的图片序列后释放内存
+    pub fn clear_prefix_cache(&mut self) {
+        self.prefix_cache.clear();
+    }
+
+    /// 前缀缓存的命中、未命中次数和当前占用
+    pub fn prefix_cache_stats(&self) -> FastVLMCacheStats {
+        self.prefix_cache.stats()
+    }
+
+    /// 清空视觉特征缓存，命中统计保留
+    pub fn clear_vision_cache(&mut self) {
+        self.vision_cache.clear();
+    }
+
+    /// 视觉特征缓存的命中、未命中次数和当前占用
+    pub fn vision_cache_stats(&self) -> FastVLMCacheStats {
+        self.vision_cache.stats()
+    }
+
+    /// 预处理图片并构建解码器的输入嵌入
+    /// 
+    /// 返回的 `FastVLMUsage` 只包含提示词和图像的 token 数量。
+    fn prepare_analysis(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<(PromptInput, GenerationParams, FastVLMUsage)> {
         let prompt = prompt.unwrap_or_else(|| self.config.default_prompt.clone());
+        let mut params = self.request_params(options)?;
         
         tracing::debug!("Starting FastVLM analysis for {}x{} image", width, height);
         
@@ -157,26 +712,54 @@ impl FastVLM {
         let preprocess_time = preprocess_start.elapsed();
         tracing::debug!("Image preprocessing completed in {:.2}ms", preprocess_time.as_millis());
         
-        // 文本生成阶段
-        let generation_start = Instant::now();
-        let generated_text = self.generate_text_sync(&image, &prompt)?;
-        let generation_time = generation_start.elapsed();
-        tracing::debug!("Text generation completed in {:.2}ms", generation_time.as_millis());
+        let system_prompt = self.config.system_prompt(options).to_string();
+        let (prompt_input, usage) = self.build_prompt_input(&image, image_hash(&image), &prompt, &system_prompt, &mut params)?;
+        Ok((prompt_input, params, usage))
+    }
+
+    /// 本次请求的生成参数，附加约束解码处理器并按种子重置随机数生成器
+    fn request_params(&mut self, options: &FastVLMRequestOptions) -> Result<GenerationParams> {
+        let mut params = self.config.generation_params(options)?;
+        
+        // 约束解码放在所有处理器之后，保证被屏蔽的 token 不会被其他处理器恢复
+        if let Some(constraint) = options.constraint.as_ref().or(self.config.constraint.as_ref()) {
+            let vocabulary = self.vocabulary.get_or_insert_with(|| Arc::new(TokenVocabulary::from_tokenizer(&self.tokenizer))).clone();
+            let processor = Arc::new(ConstrainedLogitsProcessor::new(constraint, vocabulary, &params.stop_token_ids)?);
+            params.logits_processors.push(processor.clone());
+            // 完整匹配且无法再延长时直接结束，不依赖模型选出停止 token
+            params.stopping_criteria.push(processor);
+        }
         
+        // 指定种子时重置随机数生成器，保证结果可复现
+        if let Some(seed) = options.seed.or(self.config.seed) {
+            self.rng = StdRng::seed_from_u64(seed);
+        }
+        Ok(params)
+    }
+
+    /// 把生成结果包装为分析结果，第一个输出作为主结果
+    pub(crate) fn finish_analysis(&self, start_time: Instant, usage: FastVLMUsage, outputs: Vec<GenerationOutput>) -> FastVLMAnalysisResult {
         let total_processing_time = start_time.elapsed();
+        let completion_tokens = outputs.iter().map(|output| output.completion_tokens).sum();
+        let candidates: Vec<FastVLMCandidate> = outputs.into_iter().map(FastVLMCandidate::from).collect();
+        let primary = candidates[0].clone();
         let result = FastVLMAnalysisResult {
-            text: generated_text,
+            text: primary.text,
             timestamp: start_time,
             processing_time: total_processing_time,
+            finish_reason: primary.finish_reason,
+            logprobs: primary.logprobs,
+            usage: FastVLMUsage { completion_tokens, ..usage },
+            candidates,
         };
         
-        tracing::info!("FastVLM analysis completed in {:.2}ms (preprocess: {:.2}ms, generation: {:.2}ms): {}", 
+        tracing::info!("FastVLM analysis completed in {:.2}ms ({:?}, {} tokens): {}", 
                       total_processing_time.as_millis(), 
-                      preprocess_time.as_millis(), 
-                      generation_time.as_millis(), 
+                      result.finish_reason, 
+                      result.usage.total_tokens(),
                       result.text);
         
-        Ok(result)
+        result
     }
 
     /// 异步分析图片（推荐使用）
@@ -191,6 +774,408 @@ impl FastVLM {
         // 如果需要真正的异步处理，可以在调用方使用spawn_blocking
         self.analyze_frame_sync(image_data, width, height, prompt)
     }
+
+    /// 异步分析图片，并使用单次请求的选项覆盖配置
+    pub async fn analyze_frame_with_options(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        self.analyze_frame_with_options_sync(image_data, width, height, prompt, options)
+    }
+
+    /// 异步分析图片，每生成一段新文本就调用一次 `on_delta`
+    pub async fn analyze_frame_with_callback<F>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+        on_delta: F,
+    ) -> Result<FastVLMAnalysisResult>
+    where
+        F: FnMut(&str) -> ControlFlow<()>,
+    {
+        self.analyze_frame_with_callback_sync(image_data, width, height, prompt, options, on_delta)
+    }
+
+    /// 分析图片并把回答解析为 `T`
+    /// 
+    /// 提示词末尾会附加 `T` 的 JSON Schema。回答无法解析时，把错误和上一次的回答一起交给模型
+    /// 重新生成，最多重试 `max_parse_retries` 次。`n > 1` 时使用第一个能解析的候选。
+    ///
+    /// 单次请求未设置 `max_response_length` 时至少生成 `STRUCTURED_MIN_RESPONSE_LENGTH` 个 token；
+    /// 未设置约束时，Schema 的根为对象或数组的回答被约束为一个括号完整的 JSON 值。
+    /// 所有候选都因长度上限被截断时不再重试，直接返回错误。
+    pub fn analyze_as_sync<T: DeserializeOwned + JsonSchema>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMTypedResult<T>> {
+        let prompt = prompt.unwrap_or_else(|| self.config.default_prompt.clone());
+        let schema = schema_json::<T>()?;
+        let max_retries = options.max_parse_retries.unwrap_or(self.config.max_parse_retries);
+        let options = &structured_options(&self.config, options, &schema)?;
+        
+        let mut attempt_prompt = schema_prompt(&prompt, &schema);
+        let mut last_error = None;
+        for attempt in 1..=max_retries + 1 {
+            let analysis = self.analyze_frame_with_options_sync(image_data.clone(), width, height, Some(attempt_prompt.clone()), options)?;
+            
+            let mut error = None;
+            for candidate in &analysis.candidates {
+                match parse_json_answer::<T>(&candidate.text) {
+                    Ok(value) => return Ok(FastVLMTypedResult { value, analysis, attempts: attempt }),
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    },
+                }
+            }
+            let error = error.unwrap_or_else(|| anyhow::anyhow!("No answer generated"));
+            // 截断的回答重试也会在同样的位置被截断
+            if analysis.candidates.iter().all(|candidate| candidate.finish_reason == FinishReason::MaxLength) {
+                let max_response_length = options.max_response_length.unwrap_or(self.config.max_response_length);
+                return Err(error.context(format!(
+                    "Answer was cut off by max_response_length ({} tokens) before it could be parsed as {}",
+                    max_response_length, std::any::type_name::<T>()
+                )));
+            }
+            
+            tracing::warn!("Attempt {} could not be parsed as {}: {:#}", attempt, std::any::type_name::<T>(), error);
+            attempt_prompt = repair_prompt(&prompt, &schema, &analysis.text, &error);
+            last_error = Some(error);
+        }
+        
+        let error = last_error.unwrap_or_else(|| anyhow::anyhow!("No answer generated"));
+        Err(error.context(format!("Failed to parse answer as {} after {} attempts", std::any::type_name::<T>(), max_retries + 1)))
+    }
+
+    /// 异步分析图片并把回答解析为 `T`，见 `analyze_as_sync`
+    pub async fn analyze_as<T: DeserializeOwned + JsonSchema>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMTypedResult<T>> {
+        self.analyze_as_sync(image_data, width, height, prompt, options)
+    }
+
+    /// 零样本分类：计算每个候选标签作为回答的条件概率
+    /// 
+    /// 图像和提示词只预填充一次，之后用解码器对每个标签的 token 序列（含结束符 `<|im_end|>`）
+    /// 打分，不进行采样。返回的概率在所有标签上归一化。
+    ///
+    /// 标签得分是各 token 对数概率之和，不按长度归一化，token 较多的标签天然得分更低。
+    /// 候选标签长度差异较大时，可以改写为长度相近的标签，或参考 `num_tokens` 自行比较平均值。
+    pub fn classify_sync<S: AsRef<str>>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        labels: &[S],
+    ) -> Result<FastVLMClassification> {
+        if labels.is_empty() {
+            return Err(anyhow::anyhow!("labels must not be empty"));
+        }
+        
+        let start_time = Instant::now();
+        let prompt = prompt.unwrap_or_else(|| self.config.default_prompt.clone());
+        let image = self.rgba_to_dynamic_image(image_data, width, height)?;
+        let system_prompt = self.config.system_prompt(&FastVLMRequestOptions::default()).to_string();
+        
+        let mut label_ids = Vec::with_capacity(labels.len());
+        for label in labels {
+            let mut token_ids: Vec<u32> = self.tokenizer.encode(label.as_ref(), false)
+                .map_err(|e| anyhow::anyhow!("Error encoding label {:?}: {:?}", label.as_ref(), e))?
+                .get_ids()
+                .to_vec();
+            token_ids.push(self.model_defaults.end_of_turn_token_id);
+            label_ids.push(token_ids);
+        }
+        
+        // 最长的标签代替生成长度参与上下文长度检查
+        let longest_label = label_ids.iter().map(Vec::len).max().unwrap_or(0);
+        let mut params = self.config.generation_params(&FastVLMRequestOptions::default())?;
+        params.max_new_tokens = longest_label;
+        let (prompt_input, usage) = self.build_prompt_input(&image, image_hash(&image), &prompt, &system_prompt, &mut params)?;
+        if params.max_new_tokens < longest_label {
+            return Err(anyhow::anyhow!("Labels of up to {} tokens do not fit the context length after the prompt", longest_label));
+        }
+        
+        let (prefill_logits, past_key_values) = self.decoder_forward(&prompt_input.input_embeds, &prompt_input.past)?;
+        let first_logprobs = log_softmax(&prefill_logits.view());
+        
+        let mut token_logprobs = Vec::with_capacity(labels.len());
+        for token_ids in &label_ids {
+            token_logprobs.push(self.score_continuation(&first_logprobs, &past_key_values, token_ids)?);
+        }
+        
+        let classification = FastVLMClassification {
+            scores: label_scores(labels, &token_logprobs),
+            timestamp: start_time,
+            processing_time: start_time.elapsed(),
+            usage,
+        };
+        if let Some(best) = classification.best() {
+            tracing::info!("FastVLM classification completed in {:.2}ms: {} ({:.3})",
+                          classification.processing_time.as_millis(), best.label, best.probability);
+        }
+        Ok(classification)
+    }
+
+    /// 异步零样本分类，见 `classify_sync`
+    pub async fn classify<S: AsRef<str>>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        labels: &[S],
+    ) -> Result<FastVLMClassification> {
+        self.classify_sync(image_data, width, height, prompt, labels)
+    }
+    
+    /// 开始一段关于图片的多轮对话
+    /// 
+    /// 图片只在这里编码一次，系统提示词和图片部分也在这里预填充，之后每轮
+    /// `chat_sync` 复用编码结果和 KV 缓存。`system_prompt` 为 None 时使用配置中的系统提示词。
+    pub fn start_chat_sync(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        system_prompt: Option<String>,
+    ) -> Result<ChatSession> {
+        let image = self.rgba_to_dynamic_image(image_data, width, height)?;
+        let image_features = self.get_image_features(&image)?;
+        let num_image_tokens = image_features.shape()[1];
+        let system_prompt = system_prompt
+            .unwrap_or_else(|| self.config.system_prompt(&FastVLMRequestOptions::default()).to_string());
+        let mut session = ChatSession::new(system_prompt, Some(image_features), self.empty_kv_cache());
+        
+        // 预填充到图片结束为止的前缀，之后的 token 取决于第一条用户消息
+        let (prompt_ids, image_start) = self.conversation_ids(&session.system_prompt, &[FastVLMMessage::user("")], Some(num_image_tokens))?;
+        let prefix_len = image_start.map_or(0, |start| start + num_image_tokens);
+        let input_embeds = self.prompt_embeds(&prompt_ids[..prefix_len], image_start.zip(session.image_features.as_ref()).as_slice(), 0)?;
+        let (_, present) = self.decoder_forward(&input_embeds, &session.cache)?;
+        session.cache = present;
+        session.cached_ids = prompt_ids[..prefix_len].to_vec();
+        Ok(session)
+    }
+
+    /// 把会话保存到文件，包括历史消息、图片编码结果和 KV 缓存
+    /// 
+    /// 文件同时记录模型标识，只能由相同变体和权重的模型加载。
+    pub fn save_chat_session(&self, session: &ChatSession, path: &Path) -> Result<()> {
+        let bytes = session.to_bytes(&self.fingerprint)?;
+        std::fs::write(path, bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to write chat session {}: {}", path.display(), e))
+    }
+
+    /// 从文件恢复会话，不需要重新编码图片和预填充
+    /// 
+    /// 文件由其他模型保存或已损坏时返回错误。
+    pub fn load_chat_session(&self, path: &Path) -> Result<ChatSession> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read chat session {}: {}", path.display(), e))?;
+        ChatSession::from_bytes(&bytes, &self.fingerprint)
+    }
+
+    /// 异步开始多轮对话，见 `start_chat_sync`
+    pub async fn start_chat(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        system_prompt: Option<String>,
+    ) -> Result<ChatSession> {
+        self.start_chat_sync(image_data, width, height, system_prompt)
+    }
+
+    /// 在对话中发送一条用户消息并生成回答
+    /// 
+    /// 整段对话按对话模板渲染后，与会话缓存的 token 比较，只有缓存之后的新 token
+    /// 需要预填充。回答会追加到会话历史中；出错时会话历史保持不变。
+    /// 不支持束搜索和 `n > 1`，`options.system_prompt` 在会话中不生效。
+    /// 超出上下文长度需要截断时只截断本条消息，截断后的内容写入会话历史。
+    pub fn chat_sync(
+        &mut self,
+        session: &mut ChatSession,
+        message: &str,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        let start_time = Instant::now();
+        let params = self.request_params(options)?;
+        if params.num_candidates > 1 || matches!(params.strategy, DecodingStrategy::Beam { .. }) {
+            return Err(anyhow::anyhow!("Chat sessions support neither beam search nor n > 1"));
+        }
+        
+        session.messages.push(FastVLMMessage::user(message));
+        let result = self.chat_turn(session, params, start_time);
+        match &result {
+            Ok(analysis) => session.messages.push(FastVLMMessage::assistant(analysis.text.clone())),
+            Err(_) => {
+                session.messages.pop();
+            },
+        }
+        result
+    }
+
+    /// 异步发送对话消息，见 `chat_sync`
+    pub async fn chat(
+        &mut self,
+        session: &mut ChatSession,
+        message: &str,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        self.chat_sync(session, message, options)
+    }
+
+    fn chat_turn(&mut self, session: &mut ChatSession, mut params: GenerationParams, start_time: Instant) -> Result<FastVLMAnalysisResult> {
+        let num_image_tokens = session.image_features.as_ref().map(|features| features.shape()[1]);
+        // 超出上下文长度时只截断本轮的新消息，历史保持不变
+        let message = session.messages.last().map(|message| message.content.clone()).unwrap_or_default();
+        let fitted = self.fit_prompt(&message, &mut params, |text| {
+            let mut messages = session.messages.clone();
+            if let Some(last) = messages.last_mut() {
+                last.content = text.to_string();
+            }
+            self.conversation_ids(&session.system_prompt, &messages, num_image_tokens)
+        })?;
+        if let Some(last) = session.messages.last_mut() {
+            last.content = fitted.text;
+        }
+        let (prompt_ids, image_start) = (fitted.prompt_ids, fitted.image_starts);
+        let usage = FastVLMUsage::for_prompt(prompt_ids.len(), num_image_tokens.unwrap_or(0), fitted.truncation);
+        
+        // 复用与缓存相同的前缀，只预填充之后的 token
+        let reused = reusable_prefix_len(&session.cached_ids, &prompt_ids);
+        session.truncate_cache(reused);
+        tracing::debug!("Chat turn: {} cached tokens reused, {} prefilled", reused, prompt_ids.len() - reused);
+        
+        let input_embeds = self.prompt_embeds(&prompt_ids, image_start.zip(session.image_features.as_ref()).as_slice(), reused)?;
+        
+        let mut state = GenerationState::new(input_embeds, params, session.cache.clone());
+        while !state.is_finished() {
+            self.step_generation(&mut state)?;
+        }
+        
+        // 缓存覆盖提示词和已送入解码器的生成 token
+        let past_key_values = std::mem::replace(&mut state.past_key_values, KvCache::from_tensors(Vec::new()));
+        let mut cached_ids = prompt_ids;
+        cached_ids.extend_from_slice(&state.tokens);
+        cached_ids.truncate(past_key_values.seq_len());
+        session.cache = past_key_values.prefix(cached_ids.len());
+        session.cached_ids = cached_ids;
+        
+        let output = self.finish_generation(state)?;
+        Ok(self.finish_analysis(start_time, usage, vec![output]))
+    }
+    
+    /// 渲染后对话的 token id，图像占位符展开为与视觉特征行数相同的 id
+    ///
+    /// 不带图片的对话 `num_image_tokens` 为 None。
+    fn conversation_ids(&self, system_prompt: &str, turns: &[FastVLMMessage], num_image_tokens: Option<usize>) -> Result<(Vec<u32>, Option<usize>)> {
+        let formatted_prompt = self.render_conversation(system_prompt, turns, num_image_tokens.is_some())?;
+        let (prompt_ids, image_starts) = self.encode_prompt(formatted_prompt, num_image_tokens.as_slice())?;
+        Ok((prompt_ids, image_starts.first().copied()))
+    }
+
+    /// 用用户文本 `text` 构建提示词，超出上下文长度时按需缩短
+    ///
+    /// `build` 负责把给定的用户文本渲染并分词为完整提示词。提示词加 `params.max_new_tokens`
+    /// 超出上下文长度时，由 `params.context_overflow` 决定返回错误、从左或右截断 `text`，
+    /// 还是减小 `params.max_new_tokens`。
+    fn fit_prompt<T>(
+        &self,
+        text: &str,
+        params: &mut GenerationParams,
+        build: impl Fn(&str) -> Result<(Vec<u32>, T)>,
+    ) -> Result<FittedPrompt<T>> {
+        const MAX_TRIM_ROUNDS: usize = 8;
+        
+        let mut text = text.to_string();
+        let (mut prompt_ids, mut image_starts) = build(&text)?;
+        let Some(context_length) = self.config.max_context_length else {
+            return Ok(FittedPrompt { text, prompt_ids, image_starts, truncation: None });
+        };
+        let requested_max_new_tokens = params.max_new_tokens;
+        let mut removed_prompt_tokens = 0;
+        
+        for _ in 0..MAX_TRIM_ROUNDS {
+            match fit_context(prompt_ids.len(), params.max_new_tokens, context_length, params.context_overflow)? {
+                ContextFit::Fits => {
+                    let truncation = (removed_prompt_tokens > 0 || params.max_new_tokens != requested_max_new_tokens)
+                        .then_some(FastVLMTruncation { removed_prompt_tokens, requested_max_new_tokens, max_new_tokens: params.max_new_tokens });
+                    if let Some(truncation) = &truncation {
+                        tracing::warn!("Request shortened to fit the context length {}: {:?}", context_length, truncation);
+                    }
+                    return Ok(FittedPrompt { text, prompt_ids, image_starts, truncation });
+                },
+                ContextFit::ReduceMaxNewTokens(max_new_tokens) => params.max_new_tokens = max_new_tokens,
+                ContextFit::TrimPrompt(excess) => {
+                    // 每轮重新编码，截断处的文本可能被切分成不同的 token
+                    let mut text_ids = self.tokenizer.encode(text.as_str(), false)
+                        .map_err(|e| anyhow::anyhow!("Error encoding: {:?}", e))?
+                        .get_ids()
+                        .to_vec();
+                    if excess >= text_ids.len() {
+                        return Err(anyhow::anyhow!(
+                            "Prompt of {} tokens exceeds the context length {} by {}, more than its {} tokens of user text",
+                            prompt_ids.len(), context_length, excess, text_ids.len()
+                        ));
+                    }
+                    if params.context_overflow == ContextOverflow::TruncateLeft {
+                        text_ids.drain(..excess);
+                    } else {
+                        text_ids.truncate(text_ids.len() - excess);
+                    }
+                    removed_prompt_tokens += excess;
+                    text = self.decode_tokens(&text_ids)?;
+                    (prompt_ids, image_starts) = build(&text)?;
+                },
+            }
+        }
+        Err(anyhow::anyhow!("Could not fit the prompt into the context length {}", context_length))
+    }
+
+    /// 对渲染后的提示词分词并展开图像占位符，`num_image_tokens` 中每项对应一张图片
+    ///
+    /// 返回 token id 和每张图片第一行特征的位置。
+    fn encode_prompt(&self, formatted_prompt: String, num_image_tokens: &[usize]) -> Result<(Vec<u32>, Vec<usize>)> {
+        let encoding = self.tokenizer.encode(formatted_prompt, true)
+            .map_err(|e| anyhow::anyhow!("Error encoding: {:?}", e))?;
+        expand_image_placeholders(encoding.get_ids(), self.model_defaults.image_token_id, num_image_tokens)
+    }
+
+    /// `prompt_ids[start..]` 的嵌入，并在对应位置填入每个 `(image_start, image_features)` 的特征行
+    fn prompt_embeds(&mut self, prompt_ids: &[u32], images: &[(usize, &Array3<f32>)], start: usize) -> Result<Array3<f32>> {
+        let input_ids: Vec<i64> = prompt_ids[start..].iter().map(|&id| id as i64).collect();
+        let mut input_embeds = self.get_token_embeddings(&input_ids)?;
+        for &(image_start, features) in images {
+            if features.shape()[2] != input_embeds.shape()[2] {
+                return Err(anyhow::anyhow!("Image feature dimension {} doesn't match text dimension {}",
+                                         features.shape()[2], input_embeds.shape()[2]));
+            }
+            for row in 0..features.shape()[1] {
+                let position = image_start + row;
+                if position >= start && position < prompt_ids.len() {
+                    input_embeds.slice_mut(s![0, position - start, ..]).assign(&features.slice(s![0, row, ..]));
+                }
+            }
+        }
+        Ok(input_embeds)
+    }
     
     fn rgba_to_dynamic_image(&self, data: Vec<u8>, width: u32, height: u32) -> Result<DynamicImage> {
         let expected_size = (width * height * 4) as usize;
@@ -217,41 +1202,161 @@ impl FastVLM {
         Ok(DynamicImage::ImageRgba8(image_buffer))
     }
     
-    fn generate_text_sync(&mut self, image: &DynamicImage, text: &str) -> Result<String> {
-        tracing::debug!("Processing image and generating text response");
+    /// 构建关于 `image` 的单个问题的解码器输入，开启前缀缓存时通过缓存预填充系统提示词和图片
+    ///
+    /// 前缀缓存命中时跳过视觉编码器，只嵌入问题部分的 token。
+    /// 提示词超出上下文长度时截断 `text` 或减小 `params.max_new_tokens`。
+    fn build_prompt_input(
+        &mut self,
+        image: &DynamicImage,
+        image_hash: u64,
+        text: &str,
+        system_prompt: &str,
+        params: &mut GenerationParams,
+    ) -> Result<(PromptInput, FastVLMUsage)> {
+        if !self.prefix_cache.is_enabled() {
+            return self.full_prompt_input(image, text, system_prompt, params);
+        }
+        let prefix = self.image_prefix(image, image_hash, system_prompt)?;
+        self.prompt_after_prefix(&prefix, image, text, system_prompt, params)
+    }
+    
+    /// 系统提示词和图片之后的解码器状态，优先取前缀缓存，否则预填充后写入缓存
+    fn image_prefix(&mut self, image: &DynamicImage, image_hash: u64, system_prompt: &str) -> Result<PrefixEntry> {
+        let key = PrefixKey::new(system_prompt, image_hash);
+        if self.prefix_cache.is_enabled() {
+            if let Some(entry) = self.prefix_cache.get(&key) {
+                tracing::debug!("Prefix cache hit: {} cached tokens reused", entry.ids.len());
+                return Ok(entry.clone());
+            }
+        }
         
-        // Process image using FastVLM image processor
         let image_features = self.get_image_features(image)?;
+        let num_image_tokens = image_features.shape()[1];
         tracing::debug!("Image features extracted with shape: {:?}", image_features.shape());
         
-        // Format prompt using FastVLM chat template
-        let formatted_prompt = self.format_chat_template(text);
-        tracing::debug!("Formatted prompt length: {} chars", formatted_prompt.len());
-        
-        // Tokenize the prompt
-        let encoding = self.tokenizer.encode(formatted_prompt, true)
-            .map_err(|e| anyhow::anyhow!("Error encoding: {:?}", e))?;
-
-        let input_ids: Vec<i64> = encoding.get_ids().iter().map(|&id| id as i64).collect();
-        tracing::debug!("Token IDs length: {}", input_ids.len());
+        // The tokens up to the end of the image do not depend on the question
+        let (prompt_ids, image_start) = self.conversation_ids(system_prompt, &[FastVLMMessage::user("")], Some(num_image_tokens))?;
+        let image_start = image_start.expect("one placeholder checked by expand_image_placeholders");
+        let ids = prompt_ids[..image_start + num_image_tokens].to_vec();
+        let prefix_embeds = self.prompt_embeds(&ids, &[(image_start, &image_features)], 0)?;
+        let past = self.empty_kv_cache();
+        let (_, cache) = self.decoder_forward(&prefix_embeds, &past)?;
         
-        let image_token_position = input_ids.iter().position(|&id| id == IMAGE_TOKEN_ID)
-            .unwrap_or(input_ids.len() / 2);
+        let entry = PrefixEntry { ids, num_image_tokens, cache };
+        self.prefix_cache.insert(key, entry.clone());
+        tracing::debug!("Prefilled {} prefix tokens ({} cache entries)", entry.ids.len(), self.prefix_cache.len());
+        Ok(entry)
+    }
+    
+    /// 在已预填充的 `prefix` 之后构建单个问题的解码器输入
+    ///
+    /// 对话模板渲染问题时改变了前面的 token，则退回完整预填充。
+    fn prompt_after_prefix(
+        &mut self,
+        prefix: &PrefixEntry,
+        image: &DynamicImage,
+        text: &str,
+        system_prompt: &str,
+        params: &mut GenerationParams,
+    ) -> Result<(PromptInput, FastVLMUsage)> {
+        let requested_max_new_tokens = params.max_new_tokens;
+        let fitted = self.fit_prompt(text, params, |text| {
+            self.conversation_ids(system_prompt, &[FastVLMMessage::user(text)], Some(prefix.num_image_tokens))
+        })?;
+        let prompt_ids = fitted.prompt_ids;
+        let Some(positions) = prefix.remaining_positions(&prompt_ids) else {
+            tracing::debug!("Prompt does not extend the prefilled prefix, prefilling from scratch");
+            params.max_new_tokens = requested_max_new_tokens;
+            return self.full_prompt_input(image, text, system_prompt, params);
+        };
         
-        tracing::debug!("Image token position: {}", image_token_position);
+        tracing::debug!("Reusing {} prefix tokens, {} prefilled", positions.start, positions.len());
+        let input_embeds = self.prompt_embeds(&prompt_ids, &[], positions.start)?;
+        let usage = FastVLMUsage::for_prompt(prompt_ids.len(), prefix.num_image_tokens, fitted.truncation);
+        Ok((PromptInput { input_embeds, past: prefix.cache.clone() }, usage))
+    }
+    
+    /// 从头预填充关于 `image` 的单个问题的解码器输入
+    fn full_prompt_input(
+        &mut self,
+        image: &DynamicImage,
+        text: &str,
+        system_prompt: &str,
+        params: &mut GenerationParams,
+    ) -> Result<(PromptInput, FastVLMUsage)> {
+        let image_features = self.get_image_features(image)?;
+        let num_image_tokens = image_features.shape()[1];
+        tracing::debug!("Image features extracted with shape: {:?}", image_features.shape());
         
-        let input_embeds = self.get_token_embeddings(&input_ids)?;
+        let fitted = self.fit_prompt(text, params, |text| {
+            self.conversation_ids(system_prompt, &[FastVLMMessage::user(text)], Some(num_image_tokens))
+        })?;
+        let prompt_ids = fitted.prompt_ids;
+        let image_start = fitted.image_starts.expect("one placeholder checked by expand_image_placeholders");
+        let input_embeds = self.prompt_embeds(&prompt_ids, &[(image_start, &image_features)], 0)?;
+        let usage = FastVLMUsage::for_prompt(prompt_ids.len(), num_image_tokens, fitted.truncation);
+        Ok((PromptInput { input_embeds, past: self.empty_kv_cache() }, usage))
+    }
         
-        let fused_embeds = self.fuse_image_text_embeddings(&input_embeds, &image_features, image_token_position)?;
+    /// 从头预填充文本和图片交错的提示词的解码器输入
+    ///
+    /// 提示词超出上下文长度时只截断最后一段文本。
+    fn build_parts_input(
+        &mut self,
+        parts: Vec<FastVLMContentPart>,
+        system_prompt: &str,
+        params: &mut GenerationParams,
+    ) -> Result<(PromptInput, FastVLMUsage)> {
+        let image_token = self.image_token();
+        // Text of each part, `None` for images
+        let mut segments: Vec<Option<String>> = Vec::with_capacity(parts.len());
+        let mut image_features = Vec::new();
+        for part in parts {
+            match part {
+                FastVLMContentPart::Text(text) => segments.push(Some(text)),
+                FastVLMContentPart::Image { data, width, height } => {
+                    let image = self.rgba_to_dynamic_image(data, width, height)?;
+                    image_features.push(self.get_image_features(&image)?);
+                    segments.push(None);
+                },
+            }
+        }
         
-        let generated_text = self.generate_with_decoder(&fused_embeds)?;
+        let num_image_tokens: Vec<usize> = image_features.iter().map(|features| features.shape()[1]).collect();
+        let last_text = segments.iter().rposition(Option::is_some);
+        let user_text = last_text.and_then(|i| segments[i].clone()).unwrap_or_default();
+        let fitted = self.fit_prompt(&user_text, params, |text| {
+            let content: String = segments.iter().enumerate()
+                .map(|(i, segment)| match segment {
+                    Some(_) if Some(i) == last_text => text.to_string(),
+                    Some(segment_text) => segment_text.clone(),
+                    None => format!("{}\n", image_token),
+                })
+                .collect();
+            let formatted_prompt = self.render_conversation(system_prompt, &[FastVLMMessage::user(content)], false)?;
+            self.encode_prompt(formatted_prompt, &num_image_tokens)
+        })?;
+        let (prompt_ids, image_starts) = (fitted.prompt_ids, fitted.image_starts);
+        let images: Vec<(usize, &Array3<f32>)> = image_starts.into_iter().zip(&image_features).collect();
+        let input_embeds = self.prompt_embeds(&prompt_ids, &images, 0)?;
         
-        Ok(generated_text.trim().to_string())
+        let image_tokens: usize = num_image_tokens.iter().sum();
+        let usage = FastVLMUsage::for_prompt(prompt_ids.len(), image_tokens, fitted.truncation);
+        tracing::debug!("Multi-image prompt: {} images, {} image tokens, {} total", image_features.len(), image_tokens, prompt_ids.len());
+        Ok((PromptInput { input_embeds, past: self.empty_kv_cache() }, usage))
     }
     
     fn get_image_features(&mut self, image: &DynamicImage) -> Result<Array3<f32>> {
         let batch_feature = self.image_processor.preprocess(image)?;
         
+        let cache_key = self.vision_cache.is_enabled()
+            .then(|| PixelKey::new(&batch_feature.pixel_values, &self.image_processor));
+        if let Some(image_features) = cache_key.and_then(|key| self.vision_cache.get(&key)) {
+            tracing::debug!("Vision feature cache hit");
+            return Ok(image_features.clone());
+        }
+        
         tracing::debug!("Vision encoder input shape: {:?}", batch_feature.pixel_values.shape());
         
         let outputs = self.vision_encoder.run(ort::inputs![
@@ -287,7 +1392,15 @@ impl FastVLM {
             }
         };
         
+        if image_features.shape()[0] != 1 || image_features.shape()[1] == 0 {
+            return Err(anyhow::anyhow!("Unexpected vision encoder output shape {:?}, expected [1, seq_len, hidden_dim]",
+                                     image_features.shape()));
+        }
+        
         tracing::debug!("Final image features shape: {:?}", image_features.shape());
+        if let Some(key) = cache_key {
+            self.vision_cache.insert(key, image_features.clone());
+        }
         Ok(image_features)
     }
     
@@ -305,212 +1418,423 @@ impl FastVLM {
         Ok(embeddings)
     }
     
-    fn fuse_image_text_embeddings(&self, text_embeds: &Array3<f32>, image_features: &Array3<f32>, image_token_pos: usize) -> Result<Array3<f32>> {
-        let text_seq_len = text_embeds.shape()[1];
-        let image_seq_len = image_features.shape()[1]; 
-        let hidden_dim = text_embeds.shape()[2];
-        
-        tracing::debug!("Fusing embeddings - text seq: {}, image seq: {}, token pos: {}", 
-                       text_seq_len, image_seq_len, image_token_pos);
+    /// `token_ids` 中每个 token 接在已预填充的提示词之后的对数概率
+    ///
+    /// `first_logprobs` 是提示词之后的对数概率，`past` 是提示词的缓存。
+    /// 除最后一个 token 外，其余 token 一次性送入解码器。
+    fn score_continuation(&mut self, first_logprobs: &[f32], past: &KvCache, token_ids: &[u32]) -> Result<Vec<f32>> {
+        let token_logprob = |logprobs: &[f32], token_id: u32| {
+            logprobs.get(token_id as usize).copied().unwrap_or(f32::NEG_INFINITY)
+        };
         
-        // Image features are already in the right format [1, seq_len, hidden_dim]
-        // Just need to ensure dimensions match
-        if image_features.shape()[2] != hidden_dim {
-            return Err(anyhow::anyhow!("Image feature dimension {} doesn't match text dimension {}", 
-                                     image_features.shape()[2], hidden_dim));
+        let mut logprobs = vec![token_logprob(first_logprobs, token_ids[0])];
+        if token_ids.len() > 1 {
+            let input_ids: Vec<i64> = token_ids[..token_ids.len() - 1].iter().map(|&id| id as i64).collect();
+            let token_embeds = self.get_token_embeddings(&input_ids)?;
+            let (logits, _) = self.decoder_forward_all(&token_embeds, past)?;
+            for (row, &next_token) in logits.rows().into_iter().zip(&token_ids[1..]) {
+                logprobs.push(token_logprob(&log_softmax(&row), next_token));
+            }
         }
+        Ok(logprobs)
+    }
+
+    /// 在 `past` 之上对 `inputs_embeds` 运行一次解码器
+    ///
+    /// 返回最后一个位置的 logits 和更新后的缓存。
+    fn decoder_forward(&mut self, inputs_embeds: &Array3<f32>, past: &KvCache) -> Result<(Array1<f32>, KvCache)> {
+        let (logits, present) = self.run_decoder(inputs_embeds, past, false)?;
+        Ok((logits.row(0).to_owned(), present))
+    }
+
+    /// 与 `decoder_forward` 相同，但返回每个新位置的 logits，形状为 `[seq_len, vocab]`
+    fn decoder_forward_all(&mut self, inputs_embeds: &Array3<f32>, past: &KvCache) -> Result<(Array2<f32>, KvCache)> {
+        self.run_decoder(inputs_embeds, past, true)
+    }
+
+    fn run_decoder(&mut self, inputs_embeds: &Array3<f32>, past: &KvCache, all_positions: bool) -> Result<(Array2<f32>, KvCache)> {
+        let past_len = past.seq_len();
+        let seq_len = inputs_embeds.shape()[1];
         
-        // Take a reasonable number of image tokens
-        let max_image_tokens = 256; 
-        let actual_image_tokens = image_seq_len.min(max_image_tokens);
-        let final_image_embeds = image_features.slice(s![.., ..actual_image_tokens, ..]).to_owned();
+        // Positions continue after the cached prefix, attention covers cache + new tokens
+        let position_ids: Array2<i64> = Array2::from_shape_fn((1, seq_len), |(_, i)| (past_len + i) as i64);
+        let attention_mask: Array2<i64> = Array2::ones((1, past_len + seq_len));
         
-        // Create fused embedding by inserting image features at the image token position
-        let total_seq_len = text_seq_len + actual_image_tokens;
-        let mut fused_embeds = Array3::<f32>::zeros((1, total_seq_len, hidden_dim));
+        let spec = &self.decoder_spec;
+        if past.num_layers() != spec.num_layers {
+            return Err(anyhow::anyhow!("KV cache has {} layers, decoder expects {}", past.num_layers(), spec.num_layers));
+        }
+        let mut model_inputs = ort::inputs![
+            spec.inputs_embeds.as_str() => TensorRef::from_array_view(inputs_embeds)?,
+        ];
+        if let Some(name) = &spec.position_ids {
+            model_inputs.push((name.as_str().into(), TensorRef::from_array_view(&position_ids)?.into()));
+        }
+        if let Some(name) = &spec.attention_mask {
+            model_inputs.push((name.as_str().into(), TensorRef::from_array_view(&attention_mask)?.into()));
+        }
         
-        // Copy text embeddings before image position
-        if image_token_pos > 0 {
-            fused_embeds.slice_mut(s![.., ..image_token_pos, ..])
-                .assign(&text_embeds.slice(s![.., ..image_token_pos, ..]));
+        // Add past key-value pairs
+        for (i, (key_name, value_name)) in spec.past_names.iter().enumerate() {
+            model_inputs.push((key_name.as_str().into(), TensorRef::from_array_view(past.key(i))?.into()));
+            model_inputs.push((value_name.as_str().into(), TensorRef::from_array_view(past.value(i))?.into()));
         }
         
-        // Insert image embeddings at the correct position
-        fused_embeds.slice_mut(s![.., image_token_pos..image_token_pos + actual_image_tokens, ..])
-            .assign(&final_image_embeds);
+        let outputs = self.decoder.run(model_inputs)?;
+        
+        let logits = outputs[spec.logits.as_str()].try_extract_array::<f32>()?
+            .into_dimensionality::<ndarray::Ix3>()?;
+        let out_seq_len = logits.shape()[1];
+        let vocab_size = logits.shape()[2].min(self.model_defaults.vocab_size);
+        let first_position = if all_positions { 0 } else { out_seq_len - 1 };
+        let mut position_logits = logits.slice(s![0, first_position.., ..vocab_size]).to_owned();
+        // 图像占位 token 不应被生成
+        let image_token_id = self.model_defaults.image_token_id as usize;
+        if image_token_id < vocab_size {
+            position_logits.column_mut(image_token_id).fill(f32::NEG_INFINITY);
+        }
         
-        // Copy remaining text embeddings after image
-        if image_token_pos < text_seq_len {
-            fused_embeds.slice_mut(s![.., image_token_pos + actual_image_tokens.., ..])
-                .assign(&text_embeds.slice(s![.., image_token_pos.., ..]));
+        let mut present = Vec::with_capacity(spec.num_layers * 2);
+        for (key_name, value_name) in &spec.present_names {
+            for name in [key_name, value_name] {
+                let tensor = outputs[name.as_str()]
+                    .try_extract_array::<f32>()?
+                    .into_dimensionality::<ndarray::Ix4>()?
+                    .to_owned();
+                present.push(tensor);
+            }
         }
         
-        tracing::debug!("Fused embeddings shape: {:?} (text: {}, image: {})", 
-                       fused_embeds.shape(), text_seq_len, actual_image_tokens);
+        Ok((position_logits, KvCache::from_tensors(present)))
+    }
+
+    pub(crate) fn generate_with_decoder(
+        &mut self,
+        prompt: PromptInput,
+        params: GenerationParams,
+        on_delta: &mut dyn FnMut(&str) -> ControlFlow<()>,
+    ) -> Result<GenerationOutput> {
+        tracing::debug!("Starting decoder generation with input embeds shape: {:?} after {} cached positions",
+                       prompt.input_embeds.shape(), prompt.past.seq_len());
+        
+        // Beam search only knows the winning hypothesis at the end, so it is reported as one delta
+        if let DecodingStrategy::Beam { num_beams, length_penalty, early_stopping } = params.strategy {
+            let (generated_tokens, logprobs, finish_reason) = self.beam_search(&prompt, &params, num_beams, length_penalty, early_stopping)?;
+            let output = self.build_output(&generated_tokens, &logprobs, finish_reason, &params)?;
+            if !output.text.is_empty() {
+                let _ = on_delta(&output.text);
+            }
+            return Ok(output);
+        }
         
-        Ok(fused_embeds)
+        let mut state = self.start_generation(prompt, params);
+        while !state.is_finished() {
+            if let Some(delta) = self.step_generation(&mut state)? {
+                if on_delta(&delta).is_break() {
+                    tracing::debug!("Generation cancelled by caller after {} tokens", state.tokens.len());
+                    state.finish(FinishReason::Cancelled);
+                }
+            }
+        }
+        self.finish_generation(state)
     }
 
-    fn generate_with_decoder(&mut self, input_embeds: &Array3<f32>) -> Result<String> {
-        tracing::debug!("Starting decoder generation with input embeds shape: {:?}", input_embeds.shape());
+    /// 非流式请求的全部输出，每个候选一个
+    fn generate_outputs(&mut self, prompt: PromptInput, params: GenerationParams) -> Result<Vec<GenerationOutput>> {
+        if params.num_candidates > 1 {
+            self.generate_candidates(prompt, params)
+        } else {
+            Ok(vec![self.generate_with_decoder(prompt, params, &mut |_| ControlFlow::Continue(()))?])
+        }
+    }
+
+    /// 共用一次预填充，采样 `params.num_candidates` 个回答
+    ///
+    /// 提示词只经过解码器一次，之后为每个候选复制得到的状态（KV 缓存和第一个 token 的 logits）。
+    fn generate_candidates(&mut self, prompt: PromptInput, params: GenerationParams) -> Result<Vec<GenerationOutput>> {
+        let num_candidates = params.num_candidates;
+        let mut prefilled = self.start_generation(prompt, params);
+        let (logits, present) = self.decoder_forward(&prefilled.inputs_embeds, &prefilled.past_key_values)?;
+        prefilled.pending_logits = Some(logits);
+        prefilled.past_key_values = present;
         
-        // Create position_ids for the sequence
-        let seq_len = input_embeds.shape()[1];
-        let position_ids: Array2<i64> = Array2::from_shape_fn((1, seq_len), |(_, i)| i as i64);
+        let mut outputs = Vec::with_capacity(num_candidates);
+        for candidate in 0..num_candidates {
+            let mut state = prefilled.clone();
+            while !state.is_finished() {
+                self.step_generation(&mut state)?;
+            }
+            tracing::debug!("Candidate {} finished after {} tokens", candidate, state.tokens.len());
+            outputs.push(self.finish_generation(state)?);
+        }
+        Ok(outputs)
+    }
+
+    /// 开始自回归采样生成（`DecodingStrategy::Greedy` 时为贪心解码）
+    pub(crate) fn start_generation(&self, prompt: PromptInput, params: GenerationParams) -> GenerationState {
+        GenerationState::new(prompt.input_embeds, params, prompt.past)
+    }
+
+    /// 生成一个 token，返回因此确定下来的文本
+    pub(crate) fn step_generation(&mut self, state: &mut GenerationState) -> Result<Option<String>> {
+        if state.is_finished() {
+            return Ok(None);
+        }
         
-        // Create attention mask (all ones for now)
-        let attention_mask: Array2<i64> = Array2::ones((1, seq_len));
+        if state.tokens.len() < state.params.max_new_tokens {
+            let (raw_logits, present) = match state.pending_logits.take() {
+                Some(logits) => (logits, None),
+                None => {
+                    let (logits, present) = self.decoder_forward(&state.inputs_embeds, &state.past_key_values)?;
+                    (logits, Some(present))
+                },
+            };
+            let mut last_token_logits = raw_logits.clone();
+            state.params.process_logits(&state.tokens, &mut last_token_logits);
+            let next_token_id = self.sample_token(&last_token_logits.view(), &state.sampling)?;
+            
+            // Check for end tokens
+            if state.params.is_stop_token(next_token_id) {
+                tracing::debug!("End token detected, stopping generation at step {}", state.tokens.len() + 1);
+                state.finish(FinishReason::Eos);
+            } else {
+                let logprobs = state.params.logprobs
+                    .map(|top_n| StepLogprobs::record(&raw_logits.view(), next_token_id as u32, top_n));
+                if state.push_token(next_token_id as u32, logprobs) {
+                    tracing::debug!("Repetition loop detected, stopping generation at step {}", state.tokens.len());
+                } else if let Some(present) = present {
+                    state.past_key_values = present;
+                }
+            }
+        }
         
-        // Create empty past key-value cache for first inference
-        // Based on the error, the model has at least 13 layers (layer.12 exists)
-        let num_layers = 24; // Standard for this model size
-        let num_kv_heads = 2;
-        let head_dim = 64; // 896 / 14
+        let text = self.decode_tokens(&state.tokens)?;
+        if !state.is_finished() && find_stop_sequence(&text, &state.params.stop_sequences).is_some() {
+            tracing::debug!("Stop sequence detected, stopping generation at step {}", state.tokens.len());
+            state.finish(FinishReason::StopSequence);
+        }
+        if !state.is_finished() && state.params.should_stop(&state.tokens, &text) {
+            tracing::debug!("Stopping criteria met, stopping generation at step {}", state.tokens.len());
+            state.finish(FinishReason::StoppingCriteria);
+        }
+        if !state.is_finished() && state.tokens.len() >= state.params.max_new_tokens {
+            state.finish(FinishReason::MaxLength);
+        }
         
-        let empty_kv_tensors: Vec<Array4<f32>> = (0..num_layers * 2)
-            .map(|_| Array4::<f32>::zeros((1, num_kv_heads, 0, head_dim)))
-            .collect();
+        // Prepare inputs for next step
+        if !state.is_finished() {
+            if let Some(&last_token) = state.tokens.last() {
+                state.inputs_embeds = self.get_token_embeddings(&[last_token as i64])?;
+            }
+        }
         
-        // Autoregressive generation loop
-        let mut generated_tokens = Vec::with_capacity(self.config.max_response_length);
-        let mut current_inputs_embeds = input_embeds.clone();
-        let mut current_attention_mask = attention_mask;
-        let mut current_position_ids = position_ids;
-        let mut past_key_values = empty_kv_tensors;
+        // 可能被循环检测截掉的尾部 token 先不输出
+        let streamable_text = if state.streamable_tokens().len() == state.tokens.len() {
+            text
+        } else {
+            self.decode_tokens(state.streamable_tokens())?
+        };
+        Ok(state.deltas.push(&streamable_text, &state.params.stop_sequences, state.is_finished()))
+    }
+
+    pub(crate) fn finish_generation(&self, state: GenerationState) -> Result<GenerationOutput> {
+        let finish_reason = state.finish_reason.unwrap_or(FinishReason::MaxLength);
+        self.build_output(&state.tokens, &state.logprobs, finish_reason, &state.params)
+    }
+
+    fn build_output(
+        &self,
+        generated_tokens: &[u32],
+        logprobs: &[StepLogprobs],
+        finish_reason: FinishReason,
+        params: &GenerationParams,
+    ) -> Result<GenerationOutput> {
+        tracing::debug!("Generated {} tokens total ({:?})", generated_tokens.len(), finish_reason);
         
-        for step in 0..self.config.max_response_length {
-            // Prepare inputs for current step
-            let mut model_inputs = ort::inputs![
-                "inputs_embeds" => TensorRef::from_array_view(&current_inputs_embeds)?,
-                "position_ids" => TensorRef::from_array_view(&current_position_ids)?,
-                "attention_mask" => TensorRef::from_array_view(&current_attention_mask)?,
-            ];
+        let text = if generated_tokens.is_empty() {
+            String::new()
+        } else {
+            // Decode all generated tokens to text
+            let mut generated_text = self.decode_tokens(generated_tokens)?;
+            tracing::debug!("Decoded text: '{}'", generated_text);
             
-            // Add past key-value pairs
-            for i in 0..num_layers {
-                model_inputs.push((
-                    format!("past_key_values.{}.key", i).into(),
-                    TensorRef::from_array_view(&past_key_values[i * 2])?.into()
-                ));
-                model_inputs.push((
-                    format!("past_key_values.{}.value", i).into(),
-                    TensorRef::from_array_view(&past_key_values[i * 2 + 1])?.into()
-                ));
+            // Trim the matched stop sequence and everything after it
+            if finish_reason == FinishReason::StopSequence {
+                if let Some(stop_pos) = find_stop_sequence(&generated_text, &params.stop_sequences) {
+                    generated_text.truncate(stop_pos);
+                }
             }
-            
-            // Run decoder
-            let outputs = self.decoder.run(model_inputs)?;
-            
-            let logits = outputs["logits"].try_extract_array::<f32>()?
-                .into_dimensionality::<ndarray::Ix3>()?
-                .to_owned();
-            
-            // Update past key-values for next iteration
-            let mut new_past_key_values = Vec::with_capacity(num_layers * 2);
-            for i in 0..num_layers {
-                let key = outputs[format!("present.{}.key", i)]
-                    .try_extract_array::<f32>()?
-                    .into_dimensionality::<ndarray::Ix4>()?
-                    .to_owned();
-                let value = outputs[format!("present.{}.value", i)]
-                    .try_extract_array::<f32>()?
-                    .into_dimensionality::<ndarray::Ix4>()?
-                    .to_owned();
-                new_past_key_values.push(key);
-                new_past_key_values.push(value);
+            generated_text.trim().to_string()
+        };
+        
+        let logprobs = match params.logprobs {
+            Some(_) => Some(self.resolve_logprobs(logprobs)?),
+            None => None,
+        };
+        
+        Ok(GenerationOutput { text, finish_reason, logprobs, completion_tokens: generated_tokens.len() })
+    }
+
+    /// 为每一步的原始记录附加 token 文本
+    fn resolve_logprobs(&self, records: &[StepLogprobs]) -> Result<FastVLMLogprobs> {
+        let token_text = |token_id: u32| -> Result<String> {
+            self.tokenizer.decode(&[token_id], false)
+                .map_err(|e| anyhow::anyhow!("Decode error: {:?}", e))
+        };
+        
+        let mut tokens = Vec::with_capacity(records.len());
+        for record in records {
+            let mut top_alternatives = Vec::with_capacity(record.top.len());
+            for &(token_id, logprob) in &record.top {
+                top_alternatives.push(TokenAlternative { token_id, token: token_text(token_id)?, logprob });
             }
+            tokens.push(TokenLogprob {
+                token_id: record.token_id,
+                token: token_text(record.token_id)?,
+                logprob: record.logprob,
+                top_alternatives,
+            });
+        }
+        
+        let mean_logprob = if tokens.is_empty() {
+            0.0
+        } else {
+            tokens.iter().map(|token| token.logprob).sum::<f32>() / tokens.len() as f32
+        };
+        Ok(FastVLMLogprobs { tokens, mean_logprob })
+    }
+
+    /// 束搜索，每个束有一组 past_key_values，每一步之后按父束重新排列
+    fn beam_search(
+        &mut self,
+        prompt: &PromptInput,
+        params: &GenerationParams,
+        num_beams: usize,
+        length_penalty: f32,
+        early_stopping: bool,
+    ) -> Result<(Vec<u32>, Vec<StepLogprobs>, FinishReason)> {
+        struct Beam {
+            tokens: Vec<u32>,
+            token_logprobs: Vec<StepLogprobs>,
+            sum_logprobs: f32,
+            logits: Array1<f32>,
+            past_key_values: KvCache,
+        }
+        
+        let (logits, past_key_values) = self.decoder_forward(&prompt.input_embeds, &prompt.past)?;
+        let mut beams = vec![Beam { tokens: Vec::new(), token_logprobs: Vec::new(), sum_logprobs: 0.0, logits, past_key_values }];
+        let mut finished = BeamHypotheses::new(num_beams, length_penalty, early_stopping);
+        
+        for step in 0..params.max_new_tokens {
+            // Expand every live beam with its best 2 * num_beams continuations
+            let mut candidates: Vec<(f32, usize, i64)> = Vec::new();
+            for (beam_idx, beam) in beams.iter().enumerate() {
+                let mut logits = beam.logits.clone();
+                params.process_logits(&beam.tokens, &mut logits);
+                let logprobs = log_softmax(&logits.view());
+                for token_id in top_k_indices(&logprobs, 2 * num_beams) {
+                    candidates.push((beam.sum_logprobs + logprobs[token_id], beam_idx, token_id as i64));
+                }
+            }
+            candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
             
-            // release the borrow
-            drop(outputs);
-            
-            // Get next token from the last position with temperature sampling
-            let seq_len = logits.shape()[1];
-            let vocab_size = logits.shape()[2].min(151646);
-            let last_token_logits = logits.slice(s![0, seq_len-1, ..vocab_size]);
-            let next_token_id = self.sample_token(&last_token_logits, 0.7)?;
+            // Pick the next beams, reordering each one's cache from its parent
+            let mut next_beams = Vec::with_capacity(num_beams);
+            for (rank, (sum_logprobs, beam_idx, token_id)) in candidates.into_iter().enumerate() {
+                if params.is_stop_token(token_id) {
+                    if rank < num_beams {
+                        let beam = &beams[beam_idx];
+                        finished.add(beam.tokens.clone(), beam.token_logprobs.clone(), sum_logprobs, FinishReason::Eos);
+                    }
+                    continue;
+                }
+                
+                let mut tokens = beams[beam_idx].tokens.clone();
+                tokens.push(token_id as u32);
+                let mut token_logprobs = beams[beam_idx].token_logprobs.clone();
+                if let Some(top_n) = params.logprobs {
+                    token_logprobs.push(StepLogprobs::record(&beams[beam_idx].logits.view(), token_id as u32, top_n));
+                }
+                
+                if let Some(keep) = params.loop_detection.and_then(|detector| detector.detect(&tokens)) {
+                    tokens.truncate(keep);
+                    token_logprobs.truncate(keep);
+                    finished.add(tokens, token_logprobs, sum_logprobs, FinishReason::Repetition);
+                    continue;
+                }
+                if let Some(finish_reason) = self.stop_reason(&tokens, params)? {
+                    finished.add(tokens, token_logprobs, sum_logprobs, finish_reason);
+                    continue;
+                }
+                
+                let token_embeds = self.get_token_embeddings(&[token_id])?;
+                let (logits, past_key_values) = self.decoder_forward(&token_embeds, &beams[beam_idx].past_key_values)?;
+                next_beams.push(Beam { tokens, token_logprobs, sum_logprobs, logits, past_key_values });
+                
+                if next_beams.len() == num_beams {
+                    break;
+                }
+            }
+            beams = next_beams;
             
-            // Check for end tokens
-            if next_token_id == EOS_TOKEN_ID || next_token_id == IM_END_TOKEN_ID {
-                tracing::debug!("End token detected, stopping generation at step {}", step + 1);
+            let best_live = beams.iter().map(|beam| beam.sum_logprobs).fold(f32::NEG_INFINITY, f32::max);
+            if beams.is_empty() || finished.is_done(best_live, step + 1) {
+                tracing::debug!("Beam search finished at step {}", step + 1);
                 break;
             }
-            
-            generated_tokens.push(next_token_id as u32);
-            
-            // Update the past key values
-            past_key_values = new_past_key_values;
-            
-            // Prepare inputs for next step
-            current_inputs_embeds = self.get_token_embeddings(&[next_token_id])?;
-            
-            // Update attention mask and position IDs
-            let current_seq_len = current_attention_mask.shape()[1];
-            current_attention_mask = Array2::ones((1, current_seq_len + 1));
-            current_position_ids = Array2::from_shape_fn((1, 1), |(_, _)| current_seq_len as i64);
         }
         
-        tracing::debug!("Generated {} tokens total", generated_tokens.len());
-        
-        if generated_tokens.is_empty() {
-            Ok("No response generated.".to_string())
-        } else {
-            // Decode all generated tokens to text
-            let generated_text = self.tokenizer.decode(&generated_tokens, true)
-                .map_err(|e| anyhow::anyhow!("Decode error: {:?}", e))?;
-            tracing::debug!("Decoded text: '{}'", generated_text);
-            Ok(generated_text.trim().to_string())
+        // Beams still alive at the length limit compete with the finished ones
+        for beam in beams {
+            finished.add(beam.tokens, beam.token_logprobs, beam.sum_logprobs, FinishReason::MaxLength);
         }
+        
+        Ok(finished.best().unwrap_or((Vec::new(), Vec::new(), FinishReason::MaxLength)))
     }
     
-    fn sample_token(&self, logits: &ndarray::ArrayView1<f32>, temperature: f32) -> Result<i64> {
-        // For better performance
-        const TOP_K: usize = 50;
-        
-        // Get top-k tokens with their indices
-        let mut indexed_logits: Vec<(usize, f32)> = logits.iter()
-            .enumerate()
-            .map(|(i, &logit)| (i, logit))
-            .collect();
-        
-        // Partial sort to get top-k elements
-        indexed_logits.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-        indexed_logits.truncate(TOP_K);
-        
-        // Apply temperature and softmax to top-k
-        let max_logit = indexed_logits[0].1;
-        let mut probabilities: Vec<(usize, f32)> = indexed_logits.into_iter()
-            .map(|(idx, logit)| {
-                let scaled = (logit - max_logit) / temperature;
-                (idx, scaled.exp())
-            })
-            .collect();
-        
-        let sum_exp: f32 = probabilities.iter().map(|(_, prob)| prob).sum();
-        for (_, prob) in probabilities.iter_mut() {
-            *prob /= sum_exp;
-        }
-        
-        // Simple random sampling
-        use std::time::{SystemTime, UNIX_EPOCH};
-        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-        let random_val = ((seed as u64).wrapping_mul(1103515245).wrapping_add(12345) % 1000000) as f32 / 1000000.0;
-        
-        // Sample from top-k distribution
-        let mut cumulative_prob = 0.0;
-        for (idx, prob) in probabilities {
-            cumulative_prob += prob;
-            if random_val <= cumulative_prob {
-                return Ok(idx as i64);
-            }
+    /// 不含任何位置的 KV 缓存，形状与加载的解码器一致
+    fn empty_kv_cache(&self) -> KvCache {
+        KvCache::empty(self.decoder_spec.num_layers, self.decoder_spec.num_kv_heads, self.decoder_spec.head_dim)
+    }
+    
+    fn decode_tokens(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer.decode(tokens, true)
+            .map_err(|e| anyhow::anyhow!("Decode error: {:?}", e))
+    }
+
+    /// 目前解码的文本是否命中停止字符串或用户的停止条件
+    fn stop_reason(&self, tokens: &[u32], params: &GenerationParams) -> Result<Option<FinishReason>> {
+        if params.stop_sequences.is_empty() && params.stopping_criteria.is_empty() {
+            return Ok(None);
         }
-        
-        // Fallback to most likely token
-        Ok(0)
+        let text = self.decode_tokens(tokens)?;
+        if find_stop_sequence(&text, &params.stop_sequences).is_some() {
+            return Ok(Some(FinishReason::StopSequence));
+        }
+        Ok(params.should_stop(tokens, &text).then_some(FinishReason::StoppingCriteria))
     }
 
-    fn format_chat_template(&self, text: &str) -> String {
-        // FastVLM chat template image token
-        format!("<|im_start|>system\nYou are a helpful vision assistant that describes images accurately.<|im_end|>\n<|im_start|>user\n<image>\n{}<|im_end|>\n<|im_start|>assistant\n", text)
+    fn sample_token(&mut self, logits: &ndarray::ArrayView1<f32>, sampling: &SamplingParams) -> Result<i64> {
+        // temperature -> top-k -> top-p -> min-p, see fastvlm_sampling
+        let probabilities = fastvlm_sampling::candidate_distribution(logits, sampling);
+        let token_id = fastvlm_sampling::sample_from_distribution(&probabilities, &mut self.rng)?;
+        Ok(token_id as i64)
+    }
+
+    /// 渲染对话，直到助手回合的开头
+    fn render_conversation(&self, system_prompt: &str, turns: &[FastVLMMessage], with_image: bool) -> Result<String> {
+        let mut turns = turns.to_vec();
+        // The image placeholder goes before the first question, as in the FastVLM training data
+        if let Some(first_user) = turns.iter_mut().find(|message| message.role == "user").filter(|_| with_image) {
+            first_user.content = format!("{}\n{}", self.image_token(), first_user.content);
+        }
+        self.chat_template.render(&prompt_messages(system_prompt, turns), true)
+    }
+
+    /// 图像占位 token 的文本
+    fn image_token(&self) -> String {
+        self.tokenizer.id_to_token(self.model_defaults.image_token_id)
+            .unwrap_or_else(|| "<image>".to_string())
     }
     
-}
\ No newline at end of file
+}
@@ -0,0 +1,341 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+use image::DynamicImage;
+use ndarray::{Array3, Array4};
+
+use super::fastvlm_generation::KvCache;
+use super::fastvlm_image_process::FastVLMImageProcessor;
+
+/// Hit and miss counters and current size of a cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FastVLMCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    /// Approximate memory held by the cached values
+    pub bytes: usize,
+}
+
+impl FastVLMCacheStats {
+    /// Fraction of lookups that hit, `0.0` before the first lookup
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 { 0.0 } else { self.hits as f64 / lookups as f64 }
+    }
+}
+
+/// Memory held by a cached value, used for the byte bound of `LruCache`
+pub(crate) trait ByteSize {
+    fn byte_size(&self) -> usize;
+}
+
+impl ByteSize for Array3<f32> {
+    fn byte_size(&self) -> usize {
+        self.len() * std::mem::size_of::<f32>()
+    }
+}
+
+/// Small least-recently-used cache bounded by entry count and bytes,
+/// linear lookups are fine for a handful of entries
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    max_bytes: Option<usize>,
+    /// Most recently used last
+    entries: Vec<(K, V)>,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl<K: PartialEq, V: ByteSize> LruCache<K, V> {
+    /// Cache holding at most `capacity` entries, `0` disables it
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, max_bytes: None, entries: Vec::with_capacity(capacity), bytes: 0, hits: 0, misses: 0 }
+    }
+
+    /// Additionally bound the total size of the cached values
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Whether values are kept at all
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0 && self.max_bytes != Some(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn stats(&self) -> FastVLMCacheStats {
+        FastVLMCacheStats { hits: self.hits, misses: self.misses, entries: self.entries.len(), bytes: self.bytes }
+    }
+
+    /// Look up `key`, marking it as most recently used and counting the hit or miss
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let Some(index) = self.entries.iter().position(|(entry_key, _)| entry_key == key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        let entry = self.entries.remove(index);
+        self.entries.push(entry);
+        self.entries.last().map(|(_, value)| value)
+    }
+
+    /// Insert or replace `key`, evicting least recently used entries until both bounds hold
+    ///
+    /// A value larger than the byte bound on its own is not cached.
+    pub fn insert(&mut self, key: K, value: V) {
+        let size = value.byte_size();
+        if !self.is_enabled() || self.max_bytes.is_some_and(|max_bytes| size > max_bytes) {
+            return;
+        }
+        if let Some(index) = self.entries.iter().position(|(entry_key, _)| *entry_key == key) {
+            self.remove(index);
+        }
+        while self.entries.len() >= self.capacity
+            || self.max_bytes.is_some_and(|max_bytes| self.bytes + size > max_bytes)
+        {
+            self.remove(0);
+        }
+        self.bytes += size;
+        self.entries.push((key, value));
+    }
+
+    /// Drop all entries, keeping the counters
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    fn remove(&mut self, index: usize) {
+        let (_, value) = self.entries.remove(index);
+        self.bytes -= value.byte_size();
+    }
+}
+
+/// Identifies a prompt prefix shared across questions: the system prompt and the image after it
+///
+/// The cache holding the entries belongs to one `FastVLM`, which pins the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct PrefixKey {
+    system_prompt: u64,
+    image: u64,
+}
+
+impl PrefixKey {
+    pub fn new(system_prompt: &str, image_hash: u64) -> Self {
+        let mut hasher = DefaultHasher::new();
+        system_prompt.hash(&mut hasher);
+        Self { system_prompt: hasher.finish(), image: image_hash }
+    }
+}
+
+/// Decoder state at the end of a cached prefix
+#[derive(Clone)]
+pub(crate) struct PrefixEntry {
+    /// Prompt token ids up to the last image position, the image expanded to one id per feature row
+    pub ids: Vec<u32>,
+    pub num_image_tokens: usize,
+    pub cache: KvCache,
+}
+
+impl PrefixEntry {
+    /// Positions of `prompt_ids` left to prefill on top of the cached ones, `None` when
+    /// the prompt does not extend the cached ids and has to be prefilled from scratch
+    pub fn remaining_positions(&self, prompt_ids: &[u32]) -> Option<Range<usize>> {
+        let start = self.cache.seq_len();
+        let extends = start == self.ids.len() && prompt_ids.len() > start && prompt_ids.starts_with(&self.ids);
+        extends.then_some(start..prompt_ids.len())
+    }
+}
+
+impl ByteSize for PrefixEntry {
+    fn byte_size(&self) -> usize {
+        self.cache.tensors().iter().map(|tensor| tensor.len() * std::mem::size_of::<f32>()).sum()
+    }
+}
+
+/// Identifies a vision encoder input by its shape and two independent content hashes,
+/// so a collision of one hash alone cannot return another image's features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PixelKey {
+    shape: [usize; 4],
+    hash: u64,
+    check: u64,
+}
+
+impl PixelKey {
+    /// Hash the pixel values and the preprocessing settings that produced them
+    pub fn new(pixel_values: &Array4<f32>, image_processor: &FastVLMImageProcessor) -> Self {
+        let mut hasher = DefaultHasher::new();
+        let mut check = Fnv1a::default();
+        image_processor.hash_settings(&mut hasher);
+        image_processor.hash_settings(&mut check);
+        for value in pixel_values {
+            let bits = value.to_bits();
+            hasher.write_u32(bits);
+            check.write_u32(bits);
+        }
+        let shape = pixel_values.shape();
+        Self { shape: [shape[0], shape[1], shape[2], shape[3]], hash: hasher.finish(), check: check.finish() }
+    }
+}
+
+/// 64-bit FNV-1a, unrelated to the SipHash behind `DefaultHasher`
+///
+/// `write_u32` mixes whole words instead of bytes, which is enough for a second check
+/// and keeps hashing a 1024×1024 input cheap.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    const PRIME: u64 = 0x100000001b3;
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.0 = (self.0 ^ u64::from(value)).wrapping_mul(Self::PRIME);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Content hash of an image's size and pixels
+pub(crate) fn image_hash(image: &DynamicImage) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (image.width(), image.height()).hash(&mut hasher);
+    std::mem::discriminant(image).hash(&mut hasher);
+    image.as_bytes().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastvlm::fastvlm_chat::expand_image_placeholders;
+
+    impl ByteSize for usize {
+        fn byte_size(&self) -> usize {
+            *self
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction_order() {
+        let mut cache = LruCache::<_, usize>::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        cache.insert("a", 4);
+        assert_eq!(cache.get(&"a"), Some(&4));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.stats(), FastVLMCacheStats { hits: 3, misses: 1, entries: 2, bytes: 7 });
+
+        let mut disabled = LruCache::<_, usize>::new(0);
+        disabled.insert("a", 1);
+        assert_eq!(disabled.len(), 0);
+    }
+
+    #[test]
+    fn test_lru_byte_bound() {
+        let mut cache = LruCache::<_, usize>::new(10).with_max_bytes(10);
+        cache.insert("a", 4);
+        cache.insert("b", 4);
+        cache.insert("c", 4);
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.stats().bytes, 8);
+        cache.insert("huge", 11);
+        assert_eq!(cache.get(&"huge"), None);
+        assert_eq!(cache.len(), 2);
+        cache.insert("b", 10);
+        assert_eq!((cache.len(), cache.stats().bytes), (1, 10));
+        cache.clear();
+        assert_eq!(cache.stats(), FastVLMCacheStats { hits: 0, misses: 2, entries: 0, bytes: 0 });
+    }
+
+    #[test]
+    fn test_prefix_key() {
+        let image = DynamicImage::new_rgba8(4, 4);
+        let mut other = image.to_rgba8();
+        other.put_pixel(3, 3, image::Rgba([1, 0, 0, 0]));
+        let other = DynamicImage::ImageRgba8(other);
+
+        assert_eq!(PrefixKey::new("sys", image_hash(&image)), PrefixKey::new("sys", image_hash(&image.clone())));
+        assert_ne!(PrefixKey::new("sys", image_hash(&image)), PrefixKey::new("sys", image_hash(&other)));
+        assert_ne!(PrefixKey::new("sys", image_hash(&image)), PrefixKey::new("", image_hash(&image)));
+        assert_ne!(image_hash(&image), image_hash(&DynamicImage::new_rgba8(2, 8)));
+    }
+
+    /// Cached prefix cut after the image of a conversation rendered with an empty question,
+    /// the way `FastVLM::image_prefix` builds it
+    fn prefix_entry(empty_question_ids: &[u32], num_image_tokens: usize) -> PrefixEntry {
+        let (ids, image_start) = expand_image_placeholders(empty_question_ids, 99, &[num_image_tokens]).unwrap();
+        let ids = ids[..image_start[0] + num_image_tokens].to_vec();
+        let cache = KvCache::from_tensors(vec![Array4::zeros((1, 1, ids.len(), 2)); 2]);
+        PrefixEntry { ids, num_image_tokens, cache }
+    }
+
+    #[test]
+    fn test_prefix_split_matches_full_prompt() {
+        // system [1, 2], user turn [3, <image>, question..., 4]
+        let prefix = prefix_entry(&[1, 2, 3, 99, 4], 3);
+        let (full_ids, _) = expand_image_placeholders(&[1, 2, 3, 99, 7, 8, 4], 99, &[3]).unwrap();
+
+        let positions = prefix.remaining_positions(&full_ids).unwrap();
+        let split_ids: Vec<u32> = prefix.ids.iter().chain(&full_ids[positions.clone()]).copied().collect();
+        assert_eq!(split_ids, full_ids);
+        let split_positions: Vec<usize> = (0..prefix.cache.seq_len()).chain(positions).collect();
+        assert_eq!(split_positions, (0..full_ids.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_prefix_split_falls_back() {
+        let prefix = prefix_entry(&[1, 2, 3, 99, 4], 3);
+        // The question changed a token before the image
+        let (changed, _) = expand_image_placeholders(&[1, 2, 5, 99, 7, 4], 99, &[3]).unwrap();
+        assert_eq!(prefix.remaining_positions(&changed), None);
+        // Nothing after the prefix, or a cache that does not cover exactly the prefix ids
+        assert_eq!(prefix.remaining_positions(&prefix.ids), None);
+        let (full_ids, _) = expand_image_placeholders(&[1, 2, 3, 99, 7, 4], 99, &[3]).unwrap();
+        let short = PrefixEntry { cache: prefix.cache.prefix(2), ..prefix };
+        assert_eq!(short.remaining_positions(&full_ids), None);
+    }
+
+    #[test]
+    fn test_pixel_key() {
+        let processor = FastVLMImageProcessor::new();
+        let pixels = Array4::from_shape_fn((1, 3, 4, 4), |(_, c, y, x)| (c * 16 + y * 4 + x) as f32);
+        let mut changed = pixels.clone();
+        changed[[0, 2, 3, 3]] += 1.0;
+        let reshaped = pixels.clone().into_shape_with_order((1, 3, 2, 8)).unwrap();
+
+        assert_eq!(PixelKey::new(&pixels, &processor), PixelKey::new(&pixels.clone(), &processor));
+        assert_ne!(PixelKey::new(&pixels, &processor), PixelKey::new(&changed, &processor));
+        assert_ne!(PixelKey::new(&pixels, &processor), PixelKey::new(&reshaped, &processor));
+
+        // Keys agreeing on one hash still differ on the other
+        let key = PixelKey::new(&pixels, &processor);
+        assert_ne!(key, PixelKey { check: key.check ^ 1, ..key });
+    }
+}
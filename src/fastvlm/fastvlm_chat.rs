@@ -0,0 +1,338 @@
+use anyhow::Result;
+use ndarray::{Array3, Array4};
+use serde::{Deserialize, Serialize};
+
+use super::fastvlm_chat_template::FastVLMMessage;
+use super::fastvlm_generation::KvCache;
+
+/// Magic bytes and format version of saved sessions
+const SESSION_MAGIC: &[u8; 8] = b"FVLMCHAT";
+const SESSION_VERSION: u32 = 1;
+
+/// A multi-turn conversation about one image
+///
+/// Created by `FastVLM::start_chat_sync` and advanced with `FastVLM::chat_sync`.
+/// The session keeps the encoded image and the decoder's past_key_values, so each
+/// turn only prefills the tokens that are not cached yet. Editing the history with
+/// `reset` or `truncate` is cheap: the cache is cut back to the longest prefix still
+/// shared with the conversation on the next turn.
+#[derive(Clone)]
+pub struct ChatSession {
+    pub(crate) system_prompt: String,
+    pub(crate) messages: Vec<FastVLMMessage>,
+    /// Vision encoder output, attached to the first user message
+    pub(crate) image_features: Option<Array3<f32>>,
+    pub(crate) cache: KvCache,
+    /// Token id of every cached position, image positions hold the image token id
+    pub(crate) cached_ids: Vec<u32>,
+}
+
+impl ChatSession {
+    pub(crate) fn new(system_prompt: String, image_features: Option<Array3<f32>>, cache: KvCache) -> Self {
+        Self {
+            system_prompt,
+            messages: Vec::new(),
+            image_features,
+            cache,
+            cached_ids: Vec::new(),
+        }
+    }
+
+    /// System prompt of the conversation, empty when there is no system turn
+    pub fn system_prompt(&self) -> &str {
+        &self.system_prompt
+    }
+
+    /// User and assistant messages so far, without the system turn
+    pub fn messages(&self) -> &[FastVLMMessage] {
+        &self.messages
+    }
+
+    /// Number of completed user/assistant exchanges
+    pub fn num_turns(&self) -> usize {
+        self.messages.len() / 2
+    }
+
+    /// Number of positions held in the KV cache
+    pub fn cached_tokens(&self) -> usize {
+        self.cached_ids.len()
+    }
+
+    /// Forget all turns, keeping the image and system prompt
+    pub fn reset(&mut self) {
+        self.messages.clear();
+    }
+
+    /// Keep only the first `num_turns` exchanges
+    pub fn truncate(&mut self, num_turns: usize) {
+        self.messages.truncate(num_turns * 2);
+    }
+
+    /// Drop the cached positions past `len`
+    pub(crate) fn truncate_cache(&mut self, len: usize) {
+        if len < self.cached_ids.len() {
+            self.cached_ids.truncate(len);
+            self.cache = self.cache.prefix(len);
+        }
+    }
+}
+
+impl std::fmt::Debug for ChatSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChatSession")
+            .field("system_prompt", &self.system_prompt)
+            .field("messages", &self.messages)
+            .field("has_image", &self.image_features.is_some())
+            .field("cached_tokens", &self.cached_tokens())
+            .finish()
+    }
+}
+
+/// Identifies the model a KV cache was computed with
+///
+/// Caches only make sense for the exact weights and tokenizer that produced them,
+/// so saved sessions are rejected when any of these differ.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ModelFingerprint {
+    pub variant: String,
+    /// Byte size of the vision encoder, embedding and decoder weights, external data included
+    pub file_sizes: Vec<u64>,
+    pub num_layers: usize,
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+    pub vocab_size: usize,
+    pub image_token_id: u32,
+}
+
+/// JSON header of a saved session, followed by the raw tensor data
+#[derive(Serialize, Deserialize)]
+struct SessionHeader {
+    fingerprint: ModelFingerprint,
+    system_prompt: String,
+    messages: Vec<FastVLMMessage>,
+    cached_ids: Vec<u32>,
+    image_shape: Option<[usize; 3]>,
+}
+
+impl ChatSession {
+    /// Serialize the session: magic, version, header length, JSON header, then the
+    /// KV cache and image features as little-endian f32
+    pub(crate) fn to_bytes(&self, fingerprint: &ModelFingerprint) -> Result<Vec<u8>> {
+        let header = SessionHeader {
+            fingerprint: fingerprint.clone(),
+            system_prompt: self.system_prompt.clone(),
+            messages: self.messages.clone(),
+            cached_ids: self.cached_ids.clone(),
+            image_shape: self.image_features.as_ref().map(|features| {
+                let shape = features.shape();
+                [shape[0], shape[1], shape[2]]
+            }),
+        };
+        let header = serde_json::to_vec(&header)?;
+        let num_floats = self.cache.tensors().iter().map(|tensor| tensor.len()).sum::<usize>()
+            + self.image_features.as_ref().map_or(0, |features| features.len());
+
+        let mut bytes = Vec::with_capacity(SESSION_MAGIC.len() + 12 + header.len() + num_floats * 4);
+        bytes.extend_from_slice(SESSION_MAGIC);
+        bytes.extend_from_slice(&SESSION_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(header.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        let tensors = self.cache.tensors().iter().flat_map(|tensor| tensor.iter())
+            .chain(self.image_features.iter().flat_map(|features| features.iter()));
+        for value in tensors {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        Ok(bytes)
+    }
+
+    /// Parse a session written by `to_bytes`, checking it was made with `fingerprint`
+    pub(crate) fn from_bytes(bytes: &[u8], fingerprint: &ModelFingerprint) -> Result<Self> {
+        let mut reader = ByteReader { bytes };
+        if reader.take(SESSION_MAGIC.len())? != SESSION_MAGIC {
+            return Err(anyhow::anyhow!("Not a FastVLM chat session file"));
+        }
+        let version = u32::from_le_bytes(reader.take(4)?.try_into()?);
+        if version != SESSION_VERSION {
+            return Err(anyhow::anyhow!("Unsupported chat session format version {}", version));
+        }
+        let header_len = u64::from_le_bytes(reader.take(8)?.try_into()?);
+        let header: SessionHeader = serde_json::from_slice(reader.take(usize::try_from(header_len)?)?)
+            .map_err(|e| anyhow::anyhow!("Corrupt chat session header: {}", e))?;
+
+        if header.fingerprint != *fingerprint {
+            return Err(anyhow::anyhow!(
+                "Chat session was saved with a different model ({:?}), the loaded model is {:?}",
+                header.fingerprint, fingerprint
+            ));
+        }
+
+        let seq_len = header.cached_ids.len();
+        let kv_shape = (1, fingerprint.num_kv_heads, seq_len, fingerprint.head_dim);
+        let mut tensors = Vec::with_capacity(fingerprint.num_layers * 2);
+        for _ in 0..fingerprint.num_layers * 2 {
+            tensors.push(Array4::from_shape_vec(kv_shape, reader.floats(kv_shape.0 * kv_shape.1 * kv_shape.2 * kv_shape.3)?)?);
+        }
+        let image_features = match header.image_shape {
+            Some([batch, rows, hidden]) => {
+                let len = batch.checked_mul(rows).and_then(|len| len.checked_mul(hidden))
+                    .ok_or_else(|| anyhow::anyhow!("Corrupt chat session: invalid image shape"))?;
+                Some(Array3::from_shape_vec((batch, rows, hidden), reader.floats(len)?)?)
+            },
+            None => None,
+        };
+        if !reader.bytes.is_empty() {
+            return Err(anyhow::anyhow!("Corrupt chat session: {} trailing bytes", reader.bytes.len()));
+        }
+
+        Ok(Self {
+            system_prompt: header.system_prompt,
+            messages: header.messages,
+            image_features,
+            cache: KvCache::from_tensors(tensors),
+            cached_ids: header.cached_ids,
+        })
+    }
+}
+
+/// Bounds-checked cursor over a session file
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(anyhow::anyhow!("Corrupt chat session: unexpected end of file"));
+        }
+        let (head, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn floats(&mut self, count: usize) -> Result<Vec<f32>> {
+        let len = count.checked_mul(4).ok_or_else(|| anyhow::anyhow!("Corrupt chat session: tensor too large"))?;
+        Ok(self.take(len)?
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect())
+    }
+}
+
+/// Replace each `image_token_id` placeholder with one id per feature row of the matching image
+///
+/// `num_image_tokens` holds the feature rows of every image, in prompt order. The ids must
+/// contain exactly one placeholder per image, e.g. a user message typing `<image>` is rejected.
+/// Returns the expanded ids and the position of each image's first row.
+pub(crate) fn expand_image_placeholders(ids: &[u32], image_token_id: u32, num_image_tokens: &[usize]) -> Result<(Vec<u32>, Vec<usize>)> {
+    let num_placeholders = ids.iter().filter(|&&id| id == image_token_id).count();
+    if num_placeholders != num_image_tokens.len() {
+        return Err(anyhow::anyhow!(
+            "Tokenized prompt contains {} image placeholders (token {}), expected {}",
+            num_placeholders, image_token_id, num_image_tokens.len()
+        ));
+    }
+    let mut expanded = Vec::with_capacity(ids.len() + num_image_tokens.iter().sum::<usize>());
+    let mut image_starts = Vec::with_capacity(num_image_tokens.len());
+    let mut images = num_image_tokens.iter();
+    for &id in ids {
+        if id != image_token_id {
+            expanded.push(id);
+            continue;
+        }
+        let num_tokens = *images.next().expect("placeholder count checked above");
+        image_starts.push(expanded.len());
+        expanded.extend(std::iter::repeat_n(image_token_id, num_tokens));
+    }
+    Ok((expanded, image_starts))
+}
+
+/// Number of reusable cached positions for a new prompt
+///
+/// At least one prompt token is always left to run, since the decoder needs an
+/// input to produce the next-token logits.
+pub(crate) fn reusable_prefix_len(cached: &[u32], prompt: &[u32]) -> usize {
+    let common = cached.iter().zip(prompt).take_while(|(a, b)| a == b).count();
+    common.min(prompt.len().saturating_sub(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_image_placeholders() {
+        let (ids, starts) = expand_image_placeholders(&[1, 2, 99, 3], 99, &[3]).unwrap();
+        assert_eq!(ids, vec![1, 2, 99, 99, 99, 3]);
+        assert_eq!(starts, vec![2]);
+        assert_eq!(expand_image_placeholders(&[1, 2], 99, &[]).unwrap(), (vec![1, 2], vec![]));
+
+        let (ids, starts) = expand_image_placeholders(&[99, 5, 99, 6], 99, &[2, 1]).unwrap();
+        assert_eq!(ids, vec![99, 99, 5, 99, 6]);
+        assert_eq!(starts, vec![0, 3]);
+
+        // A missing, extra or unexpected placeholder is an error rather than a guess
+        assert!(expand_image_placeholders(&[1, 2], 99, &[3]).is_err());
+        assert!(expand_image_placeholders(&[1, 99, 2, 99], 99, &[3]).is_err());
+        assert!(expand_image_placeholders(&[1, 99, 2], 99, &[]).is_err());
+    }
+
+    #[test]
+    fn test_reusable_prefix_len() {
+        assert_eq!(reusable_prefix_len(&[1, 2, 3, 4], &[1, 2, 3, 5, 6]), 3);
+        assert_eq!(reusable_prefix_len(&[1, 2, 3], &[1, 2, 3]), 2);
+        assert_eq!(reusable_prefix_len(&[], &[1, 2]), 0);
+        assert_eq!(reusable_prefix_len(&[7], &[1, 2]), 0);
+    }
+
+    fn fingerprint() -> ModelFingerprint {
+        ModelFingerprint {
+            variant: "0.5b-fp32".to_string(),
+            file_sizes: vec![1, 2, 3],
+            num_layers: 2,
+            num_kv_heads: 1,
+            head_dim: 4,
+            vocab_size: 10,
+            image_token_id: 9,
+        }
+    }
+
+    #[test]
+    fn test_session_round_trip() {
+        let fingerprint = fingerprint();
+        let kv = Array4::from_shape_fn((1, 1, 3, 4), |(_, _, position, dim)| (position * 4 + dim) as f32);
+        let mut session = ChatSession::new(
+            "Be brief.".to_string(),
+            Some(Array3::from_elem((1, 2, 4), 0.5)),
+            KvCache::from_tensors(vec![kv; 4]),
+        );
+        session.cached_ids = vec![1, 9, 9];
+        session.messages.push(FastVLMMessage::user("What is this?"));
+
+        let bytes = session.to_bytes(&fingerprint).unwrap();
+        let restored = ChatSession::from_bytes(&bytes, &fingerprint).unwrap();
+        assert_eq!(restored.messages(), session.messages());
+        assert_eq!(restored.cached_ids, session.cached_ids);
+        assert_eq!(restored.cache.key(1), session.cache.key(1));
+        assert_eq!(restored.image_features, session.image_features);
+
+        let other_model = ModelFingerprint { variant: "0.5b-q4".to_string(), ..fingerprint.clone() };
+        assert!(ChatSession::from_bytes(&bytes, &other_model).is_err());
+        assert!(ChatSession::from_bytes(&bytes[..bytes.len() - 1], &fingerprint).is_err());
+        assert!(ChatSession::from_bytes(b"not a session", &fingerprint).is_err());
+    }
+
+    #[test]
+    fn test_history_editing() {
+        let mut session = ChatSession::new("Be brief.".to_string(), None, KvCache::empty(2, 1, 4));
+        for turn in 0..3 {
+            session.messages.push(FastVLMMessage::user(format!("question {}", turn)));
+            session.messages.push(FastVLMMessage::assistant(format!("answer {}", turn)));
+        }
+        session.truncate(1);
+        assert_eq!(session.num_turns(), 1);
+        assert_eq!(session.messages()[1].content, "answer 0");
+        session.reset();
+        assert!(session.messages().is_empty());
+        assert_eq!(session.system_prompt(), "Be brief.");
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::Result;
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::{Deserialize, Serialize};
+
+/// System prompt used when neither the config nor the request sets one
+pub const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful vision assistant that describes images accurately.";
+
+/// Qwen2 ChatML template, used when the model ships no `chat_template`
+const FALLBACK_CHAT_TEMPLATE: &str = "{% for message in messages %}\
+{{ '<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>\\n' }}\
+{% endfor %}\
+{% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}";
+
+/// One turn of a conversation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FastVLMMessage {
+    /// `system`, `user` or `assistant`
+    pub role: String,
+    pub content: String,
+}
+
+impl FastVLMMessage {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self { role: role.into(), content: content.into() }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new("system", content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new("user", content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new("assistant", content)
+    }
+}
+
+/// Jinja chat template of the model, rendered like `transformers`' `apply_chat_template`
+pub(crate) struct ChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl ChatTemplate {
+    /// Compile `source`, or the built-in ChatML template when the model has none
+    pub fn new(source: Option<&str>, bos_token: Option<&str>, eos_token: Option<&str>) -> Result<Self> {
+        let mut env = Environment::new();
+        // Same whitespace handling as transformers
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        // Python string methods (`.strip()`, `.startswith()`, ...) used by HF templates
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |message: String| -> Result<(), Error> {
+            Err(Error::new(ErrorKind::InvalidOperation, message))
+        });
+        env.add_template_owned("chat", source.unwrap_or(FALLBACK_CHAT_TEMPLATE).to_string())
+            .map_err(|e| anyhow::anyhow!("Invalid chat template: {}", e))?;
+        Ok(Self {
+            env,
+            bos_token: bos_token.unwrap_or_default().to_string(),
+            eos_token: eos_token.unwrap_or_default().to_string(),
+        })
+    }
+
+    /// Render `messages`, ending with the assistant turn header when `add_generation_prompt` is set
+    pub fn render(&self, messages: &[FastVLMMessage], add_generation_prompt: bool) -> Result<String> {
+        let template = self.env.get_template("chat").expect("added in new");
+        template
+            .render(context! {
+                messages => messages,
+                add_generation_prompt => add_generation_prompt,
+                bos_token => self.bos_token,
+                eos_token => self.eos_token,
+            })
+            .map_err(|e| anyhow::anyhow!("Error rendering chat template: {}", e))
+    }
+}
+
+/// The system turn followed by `turns`, `system_prompt` of `""` omits the system turn
+pub(crate) fn prompt_messages(system_prompt: &str, turns: impl IntoIterator<Item = FastVLMMessage>) -> Vec<FastVLMMessage> {
+    let mut messages = Vec::new();
+    if !system_prompt.is_empty() {
+        messages.push(FastVLMMessage::system(system_prompt));
+    }
+    messages.extend(turns);
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallback_template_matches_chatml() {
+        let template = ChatTemplate::new(None, None, None).unwrap();
+        let messages = prompt_messages(DEFAULT_SYSTEM_PROMPT, [FastVLMMessage::user("<image>\nDescribe this image.")]);
+        assert_eq!(
+            template.render(&messages, true).unwrap(),
+            "<|im_start|>system\nYou are a helpful vision assistant that describes images accurately.<|im_end|>\n\
+             <|im_start|>user\n<image>\nDescribe this image.<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert!(!template.render(&prompt_messages("", [FastVLMMessage::user("Hi")]), false).unwrap().contains("system"));
+    }
+
+    #[test]
+    fn test_hf_style_template() {
+        let source = "{{ bos_token }}{% for message in messages %}\
+            {% if message['role'] not in ['system', 'user'] %}{{ raise_exception('Unsupported role') }}{% endif %}\
+            [{{ message['role'] | upper }}] {{ message['content'].strip() }}\n\
+            {% endfor %}";
+        let template = ChatTemplate::new(Some(source), Some("<s>"), None).unwrap();
+        let rendered = template.render(&[FastVLMMessage::user("  hello  ")], true).unwrap();
+        assert_eq!(rendered, "<s>[USER] hello\n");
+        assert!(template.render(&[FastVLMMessage::assistant("hi")], true).is_err());
+        assert!(ChatTemplate::new(Some("{% for %}"), None, None).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::time::{Duration, Instant};
+
+use super::fastvlm_generation::FastVLMUsage;
+
+/// Likelihood of one candidate label
+#[derive(Debug, Clone, PartialEq)]
+pub struct FastVLMLabelScore {
+    pub label: String,
+    /// Sum of the log-probabilities of the label tokens and the end-of-turn token
+    pub logprob: f32,
+    /// `logprob` normalized over all labels of the request
+    pub probability: f32,
+    /// Number of tokens scored, including the end-of-turn token
+    pub num_tokens: usize,
+}
+
+/// Result of scoring a fixed label set against an image
+#[derive(Debug, Clone)]
+pub struct FastVLMClassification {
+    /// One score per label, in the order the labels were given
+    pub scores: Vec<FastVLMLabelScore>,
+    pub timestamp: Instant,
+    pub processing_time: Duration,
+    /// Prompt and image token counts, `completion_tokens` is always 0
+    pub usage: FastVLMUsage,
+}
+
+impl FastVLMClassification {
+    /// The most likely label, the one given first on a tie
+    pub fn best(&self) -> Option<&FastVLMLabelScore> {
+        self.scores.iter()
+            .reduce(|best, score| if score.probability > best.probability { score } else { best })
+    }
+}
+
+/// Scores of `labels` from the log-probabilities of each label's tokens
+///
+/// A label's score is the sum over its tokens, so a longer label pays for every extra token
+/// even when each of them is likely.
+pub(crate) fn label_scores<S: AsRef<str>>(labels: &[S], token_logprobs: &[Vec<f32>]) -> Vec<FastVLMLabelScore> {
+    let logprobs: Vec<f32> = token_logprobs.iter().map(|tokens| tokens.iter().sum()).collect();
+    let probabilities = normalize_logprobs(&logprobs);
+    labels.iter().zip(token_logprobs).enumerate()
+        .map(|(i, (label, tokens))| FastVLMLabelScore {
+            label: label.as_ref().to_string(),
+            logprob: logprobs[i],
+            probability: probabilities[i],
+            num_tokens: tokens.len(),
+        })
+        .collect()
+}
+
+/// Softmax over the per-label log-likelihoods
+pub(crate) fn normalize_logprobs(logprobs: &[f32]) -> Vec<f32> {
+    let max_logprob = logprobs.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    if max_logprob == f32::NEG_INFINITY {
+        return vec![1.0 / logprobs.len() as f32; logprobs.len()];
+    }
+    let weights: Vec<f32> = logprobs.iter().map(|&logprob| (logprob - max_logprob).exp()).collect();
+    let sum: f32 = weights.iter().sum();
+    weights.into_iter().map(|weight| weight / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_logprobs() {
+        let probabilities = normalize_logprobs(&[-1.0, -2.0, f32::NEG_INFINITY]);
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!((probabilities[0] / probabilities[1] - std::f32::consts::E).abs() < 1e-4);
+        assert_eq!(probabilities[2], 0.0);
+
+        assert_eq!(normalize_logprobs(&[f32::NEG_INFINITY, f32::NEG_INFINITY]), vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_label_scores_favour_shorter_labels() {
+        // "tabby cat" has likelier tokens on average but pays for two more of them
+        let scores = label_scores(&["cat", "tabby cat"], &[vec![-0.5, -0.1], vec![-0.2, -0.2, -0.2, -0.1]]);
+        assert_eq!((scores[0].num_tokens, scores[1].num_tokens), (2, 4));
+        assert!((scores[0].logprob - -0.6).abs() < 1e-6);
+        assert!((scores[1].logprob - -0.7).abs() < 1e-6);
+        assert!((scores[0].probability / scores[1].probability - 0.1f32.exp()).abs() < 1e-4);
+        assert!((scores.iter().map(|score| score.probability).sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_best_prefers_first_of_tied_labels() {
+        let classification = |token_logprobs: &[Vec<f32>]| FastVLMClassification {
+            scores: label_scores(&["indoor", "outdoor", "unknown"], token_logprobs),
+            timestamp: Instant::now(),
+            processing_time: Duration::ZERO,
+            usage: FastVLMUsage::default(),
+        };
+        let tied = classification(&[vec![-3.0], vec![-0.75, -0.75], vec![-1.5]]);
+        assert_eq!(tied.scores[1].probability, tied.scores[2].probability);
+        assert_eq!(tied.best().unwrap().label, "outdoor");
+        let clear = classification(&[vec![-0.1], vec![-2.0], vec![-3.0]]);
+        assert_eq!(clear.best().unwrap().label, "indoor");
+        assert!(FastVLMClassification { scores: Vec::new(), ..tied }.best().is_none());
+    }
+}
@@ -0,0 +1,535 @@
+use anyhow::Result;
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::{Anchored, MatchKind};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::Tokenizer;
+
+use super::fastvlm_logits::{LogitsProcessor, StoppingCriteria};
+
+/// Restricts the generated text to a formal language
+///
+/// The whole response (before trimming) must match. Generation can still stop
+/// early through `max_response_length`, stop sequences or stopping criteria, in
+/// which case the text is a valid prefix only.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FastVLMConstraint {
+    /// A regular expression in `regex` crate syntax, implicitly anchored at both ends
+    Regex(String),
+    /// Exactly one of the given strings
+    Choice(Vec<String>),
+    /// A small GBNF-style grammar with a `root` rule, see below
+    ///
+    /// Supported: `name ::= ...` rules, `"literals"`, `[character classes]`, `.`,
+    /// grouping with `( )`, alternation with `|`, the `*`, `+`, `?` and `{m,n}`
+    /// repetitions and `#` comments. Rules may reference each other but not
+    /// recursively, so every grammar is compiled to a regular expression.
+    Grammar(String),
+}
+
+impl FastVLMConstraint {
+    /// Regular expression equivalent to the constraint
+    pub fn to_regex(&self) -> Result<String> {
+        match self {
+            FastVLMConstraint::Regex(pattern) => Ok(pattern.clone()),
+            FastVLMConstraint::Choice(choices) => {
+                if choices.is_empty() {
+                    return Err(anyhow::anyhow!("Choice constraint needs at least one choice"));
+                }
+                let alternatives: Vec<String> = choices.iter().map(|choice| regex_syntax::escape(choice)).collect();
+                Ok(format!("(?:{})", alternatives.join("|")))
+            },
+            FastVLMConstraint::Grammar(grammar) => grammar_to_regex(grammar),
+        }
+    }
+}
+
+/// Byte string of every token id, `None` for special tokens
+pub(crate) struct TokenVocabulary {
+    tokens: Vec<Option<Vec<u8>>>,
+}
+
+impl TokenVocabulary {
+    pub fn from_tokenizer(tokenizer: &Tokenizer) -> Self {
+        let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+        let char_bytes = byte_level_char_bytes();
+        let special: HashSet<u32> = tokenizer.get_added_tokens_decoder().into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, _)| id)
+            .collect();
+
+        let tokens = (0..tokenizer.get_vocab_size(true) as u32)
+            .map(|id| {
+                if special.contains(&id) {
+                    return None;
+                }
+                let token = tokenizer.id_to_token(id)?;
+                let bytes = if byte_level {
+                    token.chars().map(|c| char_bytes.get(&c).copied()).collect::<Option<Vec<u8>>>()
+                } else {
+                    byte_fallback(&token)
+                };
+                bytes.or_else(|| tokenizer.decode(&[id], false).ok().map(String::into_bytes))
+            })
+            .collect();
+        Self { tokens }
+    }
+
+    #[cfg(test)]
+    pub fn from_tokens(tokens: Vec<Option<&str>>) -> Self {
+        Self { tokens: tokens.into_iter().map(|token| token.map(|text| text.as_bytes().to_vec())).collect() }
+    }
+}
+
+/// Inverse of the GPT-2 `bytes_to_unicode` table used by byte-level BPE vocabularies
+fn byte_level_char_bytes() -> HashMap<char, u8> {
+    let mut printable: Vec<u8> = (b'!'..=b'~').chain(0xA1..=0xAC).chain(0xAE..=0xFF).collect();
+    let mut chars: Vec<char> = printable.iter().map(|&b| b as char).collect();
+    let mut next = 0u32;
+    for b in 0..=255u8 {
+        if !printable.contains(&b) {
+            printable.push(b);
+            chars.push(char::from_u32(256 + next).unwrap_or_default());
+            next += 1;
+        }
+    }
+    chars.into_iter().zip(printable).collect()
+}
+
+/// `<0xNN>` byte-fallback tokens of SentencePiece vocabularies
+fn byte_fallback(token: &str) -> Option<Vec<u8>> {
+    let hex = token.strip_prefix("<0x")?.strip_suffix('>')?;
+    u8::from_str_radix(hex, 16).ok().map(|b| vec![b])
+}
+
+/// Masks every token that would leave the constraint's language
+///
+/// The regex is compiled into a byte-level DFA. For each DFA state reached during
+/// generation the set of tokens whose bytes keep the DFA alive is computed once
+/// and cached, which lazily builds the token-level automaton. Stop tokens are
+/// allowed only where the text so far is a complete match.
+///
+/// The processor is also a `StoppingCriteria` that ends generation once the text is
+/// a complete match no token can extend, or has left the language.
+pub(crate) struct ConstrainedLogitsProcessor {
+    dfa: dense::DFA<Vec<u32>>,
+    start: StateID,
+    vocabulary: Arc<TokenVocabulary>,
+    stop_token_ids: Vec<u32>,
+    allowed: Mutex<HashMap<StateID, Arc<Vec<u32>>>>,
+}
+
+impl ConstrainedLogitsProcessor {
+    pub fn new(constraint: &FastVLMConstraint, vocabulary: Arc<TokenVocabulary>, stop_token_ids: &[u32]) -> Result<Self> {
+        if stop_token_ids.is_empty() {
+            return Err(anyhow::anyhow!("Constrained decoding needs at least one stop token to end a complete match"));
+        }
+        let pattern = constraint.to_regex()?;
+        let dfa = dense::Builder::new()
+            .configure(dense::Config::new().start_kind(StartKind::Anchored).match_kind(MatchKind::All))
+            .build(&pattern)
+            .map_err(|e| anyhow::anyhow!("Invalid constraint {:?}: {}", pattern, e))?;
+        let start = dfa.start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(|e| anyhow::anyhow!("Invalid constraint {:?}: {}", pattern, e))?;
+        Ok(Self {
+            dfa,
+            start,
+            vocabulary,
+            stop_token_ids: stop_token_ids.to_vec(),
+            allowed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// DFA state after feeding `bytes`, `None` if they leave the language
+    fn walk(&self, mut state: StateID, bytes: &[u8]) -> Option<StateID> {
+        for &b in bytes {
+            state = self.dfa.next_state(state, b);
+            if self.dfa.is_dead_state(state) || self.dfa.is_quit_state(state) {
+                return None;
+            }
+        }
+        Some(state)
+    }
+
+    /// Whether the text leading to `state` can still be completed
+    ///
+    /// DFA matches are reported one byte late, so the byte after a complete match
+    /// lands in a match state that is not dead yet but has no live successor.
+    fn is_live(&self, state: StateID) -> bool {
+        self.dfa.is_match_state(self.dfa.next_eoi_state(state))
+            || (0..=255u8).any(|b| !self.dfa.is_dead_state(self.dfa.next_state(state, b)))
+    }
+
+    fn state_after(&self, generated: &[u32]) -> Option<StateID> {
+        generated.iter().try_fold(self.start, |state, &token| {
+            let bytes = self.vocabulary.tokens.get(token as usize)?.as_deref()?;
+            self.walk(state, bytes)
+        })
+    }
+
+    /// Whether the text leading to `state` is a complete match that no token can extend
+    fn is_finished(&self, state: StateID) -> bool {
+        self.dfa.is_match_state(self.dfa.next_eoi_state(state))
+            && self.allowed_tokens(state).iter().all(|id| self.stop_token_ids.contains(id))
+    }
+
+    fn allowed_tokens(&self, state: StateID) -> Arc<Vec<u32>> {
+        if let Some(allowed) = self.allowed.lock().unwrap().get(&state) {
+            return allowed.clone();
+        }
+
+        let mut liveness: HashMap<StateID, bool> = HashMap::new();
+        let mut allowed: Vec<u32> = self.vocabulary.tokens.iter().enumerate()
+            .filter_map(|(id, bytes)| {
+                let bytes = bytes.as_deref().filter(|bytes| !bytes.is_empty())?;
+                let next = self.walk(state, bytes)?;
+                let live = *liveness.entry(next).or_insert_with(|| self.is_live(next));
+                live.then_some(id as u32)
+            })
+            .collect();
+        if self.dfa.is_match_state(self.dfa.next_eoi_state(state)) {
+            allowed.extend(&self.stop_token_ids);
+        }
+
+        let allowed = Arc::new(allowed);
+        self.allowed.lock().unwrap().insert(state, allowed.clone());
+        allowed
+    }
+}
+
+impl LogitsProcessor for ConstrainedLogitsProcessor {
+    fn process(&self, generated: &[u32], logits: &mut [f32]) {
+        // Text outside the language cannot be repaired, only allow ending it
+        let allowed = match self.state_after(generated) {
+            Some(state) => self.allowed_tokens(state),
+            None => Arc::new(self.stop_token_ids.clone()),
+        };
+        let kept: Vec<(usize, f32)> = allowed.iter()
+            .filter_map(|&id| logits.get(id as usize).map(|&logit| (id as usize, logit)))
+            .collect();
+        logits.fill(f32::NEG_INFINITY);
+        for (id, logit) in kept {
+            logits[id] = logit;
+        }
+    }
+}
+
+impl StoppingCriteria for ConstrainedLogitsProcessor {
+    fn should_stop(&self, generated: &[u32], _text: &str) -> bool {
+        self.state_after(generated).is_none_or(|state| self.is_finished(state))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum GrammarToken {
+    Ident(String),
+    Defines,
+    Literal(String),
+    Class(String),
+    Any,
+    LParen,
+    RParen,
+    Pipe,
+    Repeat(String),
+}
+
+#[derive(Debug, Clone)]
+enum GrammarExpr {
+    Alt(Vec<GrammarExpr>),
+    Seq(Vec<GrammarExpr>),
+    Literal(String),
+    Class(String),
+    Any,
+    Ref(String),
+    Repeat(Box<GrammarExpr>, String),
+}
+
+/// Compile a non-recursive GBNF-style grammar into an equivalent regex
+pub fn grammar_to_regex(grammar: &str) -> Result<String> {
+    let tokens = tokenize_grammar(grammar)?;
+    let mut rules = HashMap::new();
+    let mut pos = 0;
+    while pos < tokens.len() {
+        let (name, expr) = parse_rule(&tokens, &mut pos)?;
+        if rules.insert(name.clone(), expr).is_some() {
+            return Err(anyhow::anyhow!("Grammar rule `{}` is defined twice", name));
+        }
+    }
+    if !rules.contains_key("root") {
+        return Err(anyhow::anyhow!("Grammar has no `root` rule"));
+    }
+    render_rule("root", &rules, &mut Vec::new())
+}
+
+fn tokenize_grammar(grammar: &str) -> Result<Vec<GrammarToken>> {
+    let chars: Vec<char> = grammar.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            },
+            ':' if chars[i..].starts_with(&[':', ':', '=']) => {
+                tokens.push(GrammarToken::Defines);
+                i += 3;
+            },
+            '"' => {
+                let mut literal = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(anyhow::anyhow!("Unterminated string literal in grammar")),
+                        Some('"') => break,
+                        Some('\\') => {
+                            let (c, len) = parse_escape(&chars[i + 1..])?;
+                            literal.push(c);
+                            i += 1 + len;
+                        },
+                        Some(&c) => {
+                            literal.push(c);
+                            i += 1;
+                        },
+                    }
+                }
+                tokens.push(GrammarToken::Literal(literal));
+                i += 1;
+            },
+            '[' => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && chars[i] != ']' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                if i >= chars.len() {
+                    return Err(anyhow::anyhow!("Unterminated character class in grammar"));
+                }
+                tokens.push(GrammarToken::Class(chars[start..i].iter().collect()));
+                i += 1;
+            },
+            '{' => {
+                let start = i;
+                while i < chars.len() && chars[i] != '}' {
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(anyhow::anyhow!("Unterminated repetition in grammar"));
+                }
+                tokens.push(GrammarToken::Repeat(chars[start..=i].iter().collect()));
+                i += 1;
+            },
+            '*' | '+' | '?' => {
+                tokens.push(GrammarToken::Repeat(c.to_string()));
+                i += 1;
+            },
+            '.' => {
+                tokens.push(GrammarToken::Any);
+                i += 1;
+            },
+            '(' => {
+                tokens.push(GrammarToken::LParen);
+                i += 1;
+            },
+            ')' => {
+                tokens.push(GrammarToken::RParen);
+                i += 1;
+            },
+            '|' => {
+                tokens.push(GrammarToken::Pipe);
+                i += 1;
+            },
+            c if c.is_alphanumeric() || c == '-' || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '-' || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(GrammarToken::Ident(chars[start..i].iter().collect()));
+            },
+            c => return Err(anyhow::anyhow!("Unexpected character {:?} in grammar", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Escape sequence after a backslash, returns the character and the number of chars consumed
+fn parse_escape(chars: &[char]) -> Result<(char, usize)> {
+    match chars.first() {
+        Some('n') => Ok(('\n', 1)),
+        Some('r') => Ok(('\r', 1)),
+        Some('t') => Ok(('\t', 1)),
+        Some('x') => {
+            let hex: String = chars.iter().skip(1).take(2).collect();
+            let code = u32::from_str_radix(&hex, 16)
+                .map_err(|_| anyhow::anyhow!("Invalid \\x escape in grammar"))?;
+            Ok((char::from_u32(code).unwrap_or_default(), 3))
+        },
+        Some(&c) => Ok((c, 1)),
+        None => Err(anyhow::anyhow!("Dangling backslash in grammar")),
+    }
+}
+
+fn parse_rule(tokens: &[GrammarToken], pos: &mut usize) -> Result<(String, GrammarExpr)> {
+    let name = match (tokens.get(*pos), tokens.get(*pos + 1)) {
+        (Some(GrammarToken::Ident(name)), Some(GrammarToken::Defines)) => name.clone(),
+        _ => return Err(anyhow::anyhow!("Expected `name ::=` at grammar token {}", *pos)),
+    };
+    *pos += 2;
+    let expr = parse_alternatives(tokens, pos)?;
+    Ok((name, expr))
+}
+
+/// A rule body ends at the next `name ::=` or at the end of the grammar
+fn at_rule_end(tokens: &[GrammarToken], pos: usize) -> bool {
+    matches!(
+        (tokens.get(pos), tokens.get(pos + 1)),
+        (None, _) | (Some(GrammarToken::Ident(_)), Some(GrammarToken::Defines))
+    )
+}
+
+fn parse_alternatives(tokens: &[GrammarToken], pos: &mut usize) -> Result<GrammarExpr> {
+    let mut alternatives = vec![parse_sequence(tokens, pos)?];
+    while tokens.get(*pos) == Some(&GrammarToken::Pipe) {
+        *pos += 1;
+        alternatives.push(parse_sequence(tokens, pos)?);
+    }
+    Ok(if alternatives.len() == 1 { alternatives.remove(0) } else { GrammarExpr::Alt(alternatives) })
+}
+
+fn parse_sequence(tokens: &[GrammarToken], pos: &mut usize) -> Result<GrammarExpr> {
+    let mut items = Vec::new();
+    while !at_rule_end(tokens, *pos) && !matches!(tokens[*pos], GrammarToken::Pipe | GrammarToken::RParen) {
+        let mut item = match &tokens[*pos] {
+            GrammarToken::Literal(literal) => GrammarExpr::Literal(literal.clone()),
+            GrammarToken::Class(class) => GrammarExpr::Class(class.clone()),
+            GrammarToken::Any => GrammarExpr::Any,
+            GrammarToken::Ident(name) => GrammarExpr::Ref(name.clone()),
+            GrammarToken::LParen => {
+                *pos += 1;
+                let inner = parse_alternatives(tokens, pos)?;
+                if tokens.get(*pos) != Some(&GrammarToken::RParen) {
+                    return Err(anyhow::anyhow!("Missing `)` in grammar"));
+                }
+                inner
+            },
+            token => return Err(anyhow::anyhow!("Unexpected {:?} in grammar", token)),
+        };
+        *pos += 1;
+        while let Some(GrammarToken::Repeat(op)) = tokens.get(*pos) {
+            item = GrammarExpr::Repeat(Box::new(item), op.clone());
+            *pos += 1;
+        }
+        items.push(item);
+    }
+    Ok(GrammarExpr::Seq(items))
+}
+
+fn render_rule(name: &str, rules: &HashMap<String, GrammarExpr>, stack: &mut Vec<String>) -> Result<String> {
+    if stack.iter().any(|active| active == name) {
+        return Err(anyhow::anyhow!("Grammar rule `{}` is recursive, only non-recursive grammars are supported", name));
+    }
+    let expr = rules.get(name).ok_or_else(|| anyhow::anyhow!("Grammar rule `{}` is not defined", name))?;
+    stack.push(name.to_string());
+    let regex = render_expr(expr, rules, stack)?;
+    stack.pop();
+    Ok(regex)
+}
+
+fn render_expr(expr: &GrammarExpr, rules: &HashMap<String, GrammarExpr>, stack: &mut Vec<String>) -> Result<String> {
+    Ok(match expr {
+        GrammarExpr::Alt(alternatives) => {
+            let rendered = alternatives.iter()
+                .map(|alternative| render_expr(alternative, rules, stack))
+                .collect::<Result<Vec<_>>>()?;
+            format!("(?:{})", rendered.join("|"))
+        },
+        GrammarExpr::Seq(items) => items.iter()
+            .map(|item| render_expr(item, rules, stack))
+            .collect::<Result<Vec<_>>>()?
+            .concat(),
+        GrammarExpr::Literal(literal) => regex_syntax::escape(literal),
+        GrammarExpr::Class(class) => format!("[{}]", class),
+        GrammarExpr::Any => "(?s:.)".to_string(),
+        GrammarExpr::Ref(name) => format!("(?:{})", render_rule(name, rules, stack)?),
+        GrammarExpr::Repeat(inner, op) => format!("(?:{}){}", render_expr(inner, rules, stack)?, op),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOS: u32 = 6;
+
+    fn processor(constraint: FastVLMConstraint) -> ConstrainedLogitsProcessor {
+        let vocabulary = TokenVocabulary::from_tokens(vec![
+            Some("yes"), Some("no"), Some(","), Some("y"), Some("es"), Some("maybe"), None,
+        ]);
+        ConstrainedLogitsProcessor::new(&constraint, Arc::new(vocabulary), &[EOS]).unwrap()
+    }
+
+    fn allowed(processor: &ConstrainedLogitsProcessor, generated: &[u32]) -> Vec<u32> {
+        let mut logits = vec![0.0; 7];
+        processor.process(generated, &mut logits);
+        (0..7).filter(|&id| logits[id as usize] == 0.0).collect()
+    }
+
+    #[test]
+    fn test_grammar_constrains_tokens() {
+        let grammar = r#"
+            # comma separated answers
+            root ::= answer ("," answer)*
+            answer ::= "yes" | "no"
+        "#;
+        let processor = processor(FastVLMConstraint::Grammar(grammar.to_string()));
+        assert_eq!(allowed(&processor, &[]), vec![0, 1, 3]);
+        assert_eq!(allowed(&processor, &[3]), vec![4]);
+        assert_eq!(allowed(&processor, &[0]), vec![2, EOS]);
+        assert_eq!(allowed(&processor, &[0, 2]), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn test_choice_stops_at_complete_match() {
+        let processor = processor(FastVLMConstraint::Choice(vec!["yes".to_string(), "no".to_string()]));
+        assert!(!processor.should_stop(&[], ""));
+        assert!(!processor.should_stop(&[3], "y"));
+        assert!(processor.should_stop(&[3, 4], "yes"));
+        assert!(processor.should_stop(&[1], "no"));
+        assert_eq!(allowed(&processor, &[1]), vec![EOS]);
+
+        // Text outside the language only allows stopping instead of masking every token
+        assert!(processor.should_stop(&[5], "maybe"));
+        assert_eq!(allowed(&processor, &[5]), vec![EOS]);
+
+        let vocabulary = Arc::new(TokenVocabulary::from_tokens(vec![Some("yes")]));
+        assert!(ConstrainedLogitsProcessor::new(&FastVLMConstraint::Regex("yes".to_string()), vocabulary, &[]).is_err());
+    }
+
+    #[test]
+    fn test_choice_is_literal() {
+        let constraint = FastVLMConstraint::Choice(vec!["a.b".to_string(), "c".to_string()]);
+        assert_eq!(constraint.to_regex().unwrap(), r"(?:a\.b|c)");
+        assert!(FastVLMConstraint::Choice(Vec::new()).to_regex().is_err());
+    }
+
+    #[test]
+    fn test_recursive_grammar_is_rejected() {
+        assert!(grammar_to_regex(r#"root ::= "(" root ")" | "x""#).is_err());
+        assert!(grammar_to_regex(r#"answer ::= "x""#).is_err());
+        assert_eq!(grammar_to_regex(r#"root ::= [0-9]{1,3} "%""#).unwrap(), "(?:[0-9]){1,3}%");
+    }
+
+    #[test]
+    fn test_byte_level_table() {
+        let char_bytes = byte_level_char_bytes();
+        assert_eq!(char_bytes.len(), 256);
+        assert_eq!(char_bytes[&'Ġ'], b' ');
+        assert_eq!(char_bytes[&'a'], b'a');
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::Result;
+
+/// What to do when the prompt plus `max_response_length` exceeds the context length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextOverflow {
+    /// Fail the request
+    #[default]
+    Error,
+    /// Drop tokens from the start of the user text
+    TruncateLeft,
+    /// Drop tokens from the end of the user text
+    TruncateRight,
+    /// Generate fewer tokens, failing only when the prompt alone does not fit
+    ReduceMaxTokens,
+}
+
+/// How a request was shortened to fit the context length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FastVLMTruncation {
+    /// Tokens removed from the user text
+    pub removed_prompt_tokens: usize,
+    /// `max_response_length` of the request
+    pub requested_max_new_tokens: usize,
+    /// Tokens the decoder was actually allowed to generate
+    pub max_new_tokens: usize,
+}
+
+/// Prompt built around a user text that may have been trimmed to fit the context length
+pub(crate) struct FittedPrompt<T> {
+    /// The user text actually used
+    pub text: String,
+    pub prompt_ids: Vec<u32>,
+    pub image_starts: T,
+    pub truncation: Option<FastVLMTruncation>,
+}
+
+/// Adjustment needed for one request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContextFit {
+    Fits,
+    /// Lower `max_new_tokens` to this value
+    ReduceMaxNewTokens(usize),
+    /// Remove this many tokens from the user text
+    TrimPrompt(usize),
+}
+
+/// Decide how a prompt of `prompt_len` tokens followed by `max_new_tokens` fits into `context_length`
+pub(crate) fn fit_context(prompt_len: usize, max_new_tokens: usize, context_length: usize, overflow: ContextOverflow) -> Result<ContextFit> {
+    let total = prompt_len + max_new_tokens;
+    if total <= context_length {
+        return Ok(ContextFit::Fits);
+    }
+    match overflow {
+        ContextOverflow::Error => Err(anyhow::anyhow!(
+            "Prompt of {} tokens plus max_response_length {} exceeds the context length {}",
+            prompt_len, max_new_tokens, context_length
+        )),
+        ContextOverflow::ReduceMaxTokens if prompt_len < context_length => {
+            Ok(ContextFit::ReduceMaxNewTokens(context_length - prompt_len))
+        },
+        ContextOverflow::ReduceMaxTokens => Err(anyhow::anyhow!(
+            "Prompt of {} tokens leaves no room to generate within the context length {}",
+            prompt_len, context_length
+        )),
+        ContextOverflow::TruncateLeft | ContextOverflow::TruncateRight => Ok(ContextFit::TrimPrompt(total - context_length)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fit_context() {
+        assert_eq!(fit_context(100, 28, 128, ContextOverflow::Error).unwrap(), ContextFit::Fits);
+        assert!(fit_context(100, 29, 128, ContextOverflow::Error).is_err());
+        assert_eq!(fit_context(100, 50, 128, ContextOverflow::ReduceMaxTokens).unwrap(), ContextFit::ReduceMaxNewTokens(28));
+        assert!(fit_context(128, 50, 128, ContextOverflow::ReduceMaxTokens).is_err());
+        assert_eq!(fit_context(140, 30, 128, ContextOverflow::TruncateLeft).unwrap(), ContextFit::TrimPrompt(42));
+        assert_eq!(fit_context(140, 30, 128, ContextOverflow::TruncateRight).unwrap(), ContextFit::TrimPrompt(42));
+    }
+}
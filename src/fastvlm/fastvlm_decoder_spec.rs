@@ -0,0 +1,216 @@
+use anyhow::Result;
+use ort::session::Session;
+use ort::tensor::TensorElementType;
+use serde_json::Value;
+
+/// Decoder architecture and I/O names, read from the ONNX session and `config.json`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DecoderSpec {
+    pub num_layers: usize,
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+    pub inputs_embeds: String,
+    pub position_ids: Option<String>,
+    pub attention_mask: Option<String>,
+    pub logits: String,
+    /// `(key, value)` input names per layer
+    pub past_names: Vec<(String, String)>,
+    /// `(key, value)` output names per layer
+    pub present_names: Vec<(String, String)>,
+}
+
+/// Name, element type and shape (`-1` for dynamic dimensions) of one session input or output
+#[derive(Debug, Clone)]
+pub(crate) struct TensorInfo {
+    pub name: String,
+    pub dtype: Option<TensorElementType>,
+    pub shape: Vec<i64>,
+}
+
+const PAST_PREFIXES: [&str; 3] = ["past_key_values.", "past_key_values_", "past."];
+const PRESENT_PREFIXES: [&str; 4] = ["present.", "present_key_values.", "present_key_values_", "present_"];
+
+impl DecoderSpec {
+    pub fn from_session(session: &Session, config: Option<&Value>) -> Result<Self> {
+        let inputs: Vec<TensorInfo> = session.inputs.iter()
+            .map(|input| TensorInfo {
+                name: input.name.clone(),
+                dtype: input.input_type.tensor_type(),
+                shape: input.input_type.tensor_shape().map(|shape| shape.to_vec()).unwrap_or_default(),
+            })
+            .collect();
+        let outputs: Vec<TensorInfo> = session.outputs.iter()
+            .map(|output| TensorInfo {
+                name: output.name.clone(),
+                dtype: output.output_type.tensor_type(),
+                shape: output.output_type.tensor_shape().map(|shape| shape.to_vec()).unwrap_or_default(),
+            })
+            .collect();
+        Self::from_io(&inputs, &outputs, config)
+    }
+
+    pub fn from_io(inputs: &[TensorInfo], outputs: &[TensorInfo], config: Option<&Value>) -> Result<Self> {
+        let find = |tensors: &[TensorInfo], name: &str| tensors.iter().any(|tensor| tensor.name == name).then(|| name.to_string());
+
+        let inputs_embeds = find(inputs, "inputs_embeds")
+            .ok_or_else(|| anyhow::anyhow!("Incompatible decoder export: no `inputs_embeds` input"))?;
+        let logits = find(outputs, "logits")
+            .ok_or_else(|| anyhow::anyhow!("Incompatible decoder export: no `logits` output"))?;
+
+        let past_names = kv_names(inputs, &PAST_PREFIXES, "input")?;
+        let present_names = kv_names(outputs, &PRESENT_PREFIXES, "output")?;
+        if past_names.len() != present_names.len() {
+            return Err(anyhow::anyhow!(
+                "Incompatible decoder export: {} past layers but {} present layers",
+                past_names.len(), present_names.len()
+            ));
+        }
+        let num_layers = past_names.len();
+
+        // Past key shape is [batch, num_kv_heads, past_seq_len, head_dim]
+        let past_key = inputs.iter().find(|tensor| tensor.name == past_names[0].0).expect("name taken from inputs");
+        if past_key.dtype == Some(TensorElementType::Float16) {
+            return Err(anyhow::anyhow!(
+                "`{}` is float16: fp16 exports are not supported yet, use an fp32 or quantized (q8, q4, bnb4) variant",
+                past_key.name
+            ));
+        }
+        if past_key.dtype != Some(TensorElementType::Float32) {
+            return Err(anyhow::anyhow!(
+                "Unsupported KV cache dtype {:?} in `{}`, only float32 decoder exports are supported",
+                past_key.dtype, past_key.name
+            ));
+        }
+        if past_key.shape.len() != 4 {
+            return Err(anyhow::anyhow!(
+                "Incompatible decoder export: `{}` has rank {}, expected [batch, heads, seq, head_dim]",
+                past_key.name, past_key.shape.len()
+            ));
+        }
+
+        let text_config = config.map(|config| config.get("text_config").unwrap_or(config));
+        let config_usize = |key: &str| text_config.and_then(|config| config.get(key)).and_then(Value::as_u64).map(|v| v as usize);
+        let static_dim = |dim: i64| (dim > 0).then_some(dim as usize);
+
+        let num_kv_heads = static_dim(past_key.shape[1])
+            .or_else(|| config_usize("num_key_value_heads"))
+            .or_else(|| config_usize("num_attention_heads"))
+            .ok_or_else(|| anyhow::anyhow!("Cannot determine the number of KV heads: dynamic in the export and missing from config.json"))?;
+        let head_dim = static_dim(past_key.shape[3])
+            .or_else(|| config_usize("head_dim"))
+            .or_else(|| Some(config_usize("hidden_size")? / config_usize("num_attention_heads")?))
+            .ok_or_else(|| anyhow::anyhow!("Cannot determine the head dimension: dynamic in the export and missing from config.json"))?;
+
+        if let Some(config_layers) = config_usize("num_hidden_layers") {
+            if config_layers != num_layers {
+                return Err(anyhow::anyhow!(
+                    "config.json declares {} layers but the decoder export has {}",
+                    config_layers, num_layers
+                ));
+            }
+        }
+
+        Ok(Self {
+            num_layers,
+            num_kv_heads,
+            head_dim,
+            inputs_embeds,
+            position_ids: find(inputs, "position_ids"),
+            attention_mask: find(inputs, "attention_mask"),
+            logits,
+            past_names,
+            present_names,
+        })
+    }
+}
+
+/// Per-layer `(key, value)` names, checking that layers are numbered 0..n without gaps
+fn kv_names(tensors: &[TensorInfo], prefixes: &[&str], kind: &str) -> Result<Vec<(String, String)>> {
+    let mut layers: Vec<(Option<String>, Option<String>)> = Vec::new();
+    for tensor in tensors {
+        let Some((layer, is_key)) = parse_kv_name(&tensor.name, prefixes) else { continue };
+        if layers.len() <= layer {
+            layers.resize(layer + 1, (None, None));
+        }
+        let slot = if is_key { &mut layers[layer].0 } else { &mut layers[layer].1 };
+        *slot = Some(tensor.name.clone());
+    }
+    if layers.is_empty() {
+        return Err(anyhow::anyhow!("Incompatible decoder export: no past key/value {}s found", kind));
+    }
+    layers.into_iter().enumerate()
+        .map(|(layer, names)| match names {
+            (Some(key), Some(value)) => Ok((key, value)),
+            _ => Err(anyhow::anyhow!("Incompatible decoder export: layer {} is missing its key or value {}", layer, kind)),
+        })
+        .collect()
+}
+
+/// `past_key_values.3.key` -> `(3, true)`, also accepting `_` as separator
+fn parse_kv_name(name: &str, prefixes: &[&str]) -> Option<(usize, bool)> {
+    let rest = prefixes.iter().find_map(|prefix| name.strip_prefix(prefix))?;
+    let (layer, kind) = rest.split_once(['.', '_'])?;
+    let is_key = match kind {
+        "key" => true,
+        "value" => false,
+        _ => return None,
+    };
+    Some((layer.parse().ok()?, is_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tensor(name: &str, shape: &[i64]) -> TensorInfo {
+        TensorInfo { name: name.to_string(), dtype: Some(TensorElementType::Float32), shape: shape.to_vec() }
+    }
+
+    fn decoder_io(layers: usize, kv_shape: &[i64]) -> (Vec<TensorInfo>, Vec<TensorInfo>) {
+        let mut inputs = vec![tensor("inputs_embeds", &[-1, -1, 896]), tensor("attention_mask", &[-1, -1])];
+        let mut outputs = vec![tensor("logits", &[-1, -1, 151936])];
+        for i in 0..layers {
+            for kind in ["key", "value"] {
+                inputs.push(tensor(&format!("past_key_values.{}.{}", i, kind), kv_shape));
+                outputs.push(tensor(&format!("present.{}.{}", i, kind), kv_shape));
+            }
+        }
+        (inputs, outputs)
+    }
+
+    #[test]
+    fn test_spec_from_static_shapes() {
+        let (inputs, outputs) = decoder_io(24, &[-1, 2, -1, 64]);
+        let spec = DecoderSpec::from_io(&inputs, &outputs, None).unwrap();
+        assert_eq!((spec.num_layers, spec.num_kv_heads, spec.head_dim), (24, 2, 64));
+        assert_eq!(spec.past_names[3].1, "past_key_values.3.value");
+        assert_eq!(spec.present_names[23].0, "present.23.key");
+        assert_eq!(spec.position_ids, None);
+    }
+
+    #[test]
+    fn test_spec_falls_back_to_config() {
+        let (inputs, outputs) = decoder_io(28, &[-1, -1, -1, -1]);
+        let config = serde_json::json!({
+            "text_config": { "num_hidden_layers": 28, "num_key_value_heads": 2, "hidden_size": 1536, "num_attention_heads": 12 }
+        });
+        let spec = DecoderSpec::from_io(&inputs, &outputs, Some(&config)).unwrap();
+        assert_eq!((spec.num_layers, spec.num_kv_heads, spec.head_dim), (28, 2, 128));
+
+        assert!(DecoderSpec::from_io(&inputs, &outputs, None).is_err());
+        let wrong_layers = serde_json::json!({ "num_hidden_layers": 24, "num_key_value_heads": 2, "head_dim": 128 });
+        assert!(DecoderSpec::from_io(&inputs, &outputs, Some(&wrong_layers)).is_err());
+    }
+
+    #[test]
+    fn test_incompatible_exports_fail() {
+        let (mut inputs, outputs) = decoder_io(2, &[-1, 2, -1, 64]);
+        inputs.retain(|tensor| tensor.name != "past_key_values.1.value");
+        assert!(DecoderSpec::from_io(&inputs, &outputs, None).is_err());
+
+        let (mut inputs, outputs) = decoder_io(2, &[-1, 2, -1, 64]);
+        inputs.iter_mut().for_each(|tensor| tensor.dtype = Some(TensorElementType::Float16));
+        let error = DecoderSpec::from_io(&inputs, &outputs, None).unwrap_err().to_string();
+        assert!(error.contains("fp16 exports are not supported"), "{}", error);
+    }
+}
@@ -0,0 +1,533 @@
+use anyhow::Result;
+use ndarray::{s, Array1, Array3, Array4, ArrayView1};
+use std::sync::Arc;
+
+use super::fastvlm_context::{ContextOverflow, FastVLMTruncation};
+use super::fastvlm_logits::{LogitsProcessor, StoppingCriteria};
+use super::fastvlm_sampling::{PenaltyParams, SamplingParams};
+use super::fastvlm_stream::TextDeltaDecoder;
+
+/// Why generation stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model produced an end-of-sequence token
+    Eos,
+    /// The decoded text contained one of the stop sequences
+    StopSequence,
+    /// `max_response_length` tokens were generated
+    MaxLength,
+    /// The loop detector found a repeating n-gram cycle
+    Repetition,
+    /// The caller stopped generation from a streaming callback
+    Cancelled,
+    /// One of the user `StoppingCriteria` asked to stop
+    StoppingCriteria,
+}
+
+/// Token counts of one analysis
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FastVLMUsage {
+    /// Text tokens of the templated prompt, image placeholders excluded
+    pub prompt_tokens: usize,
+    /// Vision encoder features inserted into the decoder input
+    pub image_tokens: usize,
+    /// Tokens generated by the decoder, excluding the end-of-sequence token
+    pub completion_tokens: usize,
+    /// Set when the request was shortened to fit the context length
+    pub truncation: Option<FastVLMTruncation>,
+}
+
+impl FastVLMUsage {
+    /// Usage of a prompt of `prompt_len` decoder positions, `image_tokens` of which hold image features
+    pub(crate) fn for_prompt(prompt_len: usize, image_tokens: usize, truncation: Option<FastVLMTruncation>) -> Self {
+        Self { prompt_tokens: prompt_len - image_tokens, image_tokens, completion_tokens: 0, truncation }
+    }
+
+    /// Total sequence length seen by the decoder
+    pub fn total_tokens(&self) -> usize {
+        self.prompt_tokens + self.image_tokens + self.completion_tokens
+    }
+}
+
+/// Log-probability of one generated token and the best alternatives at that step
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token_id: u32,
+    /// The token decoded on its own (may be a partial character)
+    pub token: String,
+    /// Log-probability under the model's distribution, before penalties and sampling filters
+    pub logprob: f32,
+    /// Most likely tokens at this step, best first
+    pub top_alternatives: Vec<TokenAlternative>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenAlternative {
+    pub token_id: u32,
+    pub token: String,
+    pub logprob: f32,
+}
+
+/// One of the `n` continuations generated for a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct FastVLMCandidate {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub logprobs: Option<FastVLMLogprobs>,
+    pub completion_tokens: usize,
+}
+
+/// Per-token confidence of a generated response
+#[derive(Debug, Clone, PartialEq)]
+pub struct FastVLMLogprobs {
+    pub tokens: Vec<TokenLogprob>,
+    /// Mean log-probability over all generated tokens, `0.0` when nothing was generated
+    pub mean_logprob: f32,
+}
+
+/// Raw logprob record of one step, resolved into `TokenLogprob` once generation ends
+#[derive(Debug, Clone)]
+pub(crate) struct StepLogprobs {
+    pub token_id: u32,
+    pub logprob: f32,
+    pub top: Vec<(u32, f32)>,
+}
+
+impl StepLogprobs {
+    pub fn record(logits: &ArrayView1<f32>, token_id: u32, top_n: usize) -> Self {
+        let logprobs = log_softmax(logits);
+        Self {
+            token_id,
+            logprob: logprobs.get(token_id as usize).copied().unwrap_or(f32::NEG_INFINITY),
+            top: top_k_indices(&logprobs, top_n).into_iter()
+                .map(|idx| (idx as u32, logprobs[idx]))
+                .collect(),
+        }
+    }
+}
+
+/// Stops generation when the output ends in the same n-gram repeated several times
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopDetection {
+    /// Longest cycle (in tokens) that is looked for
+    pub max_ngram_size: usize,
+    /// How many back-to-back copies of the cycle count as a loop
+    pub min_repeats: usize,
+}
+
+impl Default for LoopDetection {
+    fn default() -> Self {
+        Self {
+            max_ngram_size: 10,
+            min_repeats: 3,
+        }
+    }
+}
+
+impl LoopDetection {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_ngram_size == 0 || self.min_repeats < 2 {
+            return Err(anyhow::anyhow!("loop detection needs max_ngram_size >= 1 and min_repeats >= 2"));
+        }
+        Ok(())
+    }
+
+    /// If `tokens` ends in a repeating cycle, return how many tokens to keep
+    ///
+    /// The kept prefix still contains one copy of the cycle.
+    pub fn detect(&self, tokens: &[u32]) -> Option<usize> {
+        (1..=self.max_ngram_size).find_map(|period| {
+            let span = period * self.min_repeats;
+            if span > tokens.len() {
+                return None;
+            }
+            let tail = &tokens[tokens.len() - span..];
+            let is_cycle = (period..span).all(|i| tail[i] == tail[i - period]);
+            is_cycle.then(|| tokens.len() - period * (self.min_repeats - 1))
+        })
+    }
+
+    /// Most tokens one `detect` call can drop from the end of `tokens`
+    pub fn max_removed(&self) -> usize {
+        self.max_ngram_size * self.min_repeats.saturating_sub(1)
+    }
+}
+
+/// How the decoder picks the next token
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DecodingStrategy {
+    /// Random sampling with temperature / top-k / top-p / min-p
+    #[default]
+    Sample,
+    /// Always take the most likely token
+    Greedy,
+    /// Beam search keeping `num_beams` hypotheses per step
+    Beam {
+        num_beams: usize,
+        /// Scores are divided by `length ^ length_penalty`, > 0 favours longer outputs
+        length_penalty: f32,
+        /// Stop as soon as `num_beams` hypotheses have finished
+        early_stopping: bool,
+    },
+}
+
+impl DecodingStrategy {
+    pub fn validate(&self) -> Result<()> {
+        if let DecodingStrategy::Beam { num_beams, length_penalty, .. } = *self {
+            if num_beams == 0 {
+                return Err(anyhow::anyhow!("num_beams must be at least 1"));
+            }
+            if !length_penalty.is_finite() {
+                return Err(anyhow::anyhow!("length_penalty must be finite, got {}", length_penalty));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fully resolved parameters for one call of the generation loop
+#[derive(Debug, Clone)]
+pub(crate) struct GenerationParams {
+    pub max_new_tokens: usize,
+    pub strategy: DecodingStrategy,
+    pub sampling: SamplingParams,
+    pub penalties: PenaltyParams,
+    pub loop_detection: Option<LoopDetection>,
+    pub stop_token_ids: Vec<u32>,
+    pub stop_sequences: Vec<String>,
+    /// Number of top alternatives to record per token, `None` disables logprobs
+    pub logprobs: Option<usize>,
+    /// Number of independent continuations sampled from one prefill
+    pub num_candidates: usize,
+    /// User processors, run after the penalties
+    pub logits_processors: Vec<Arc<dyn LogitsProcessor>>,
+    pub stopping_criteria: Vec<Arc<dyn StoppingCriteria>>,
+    /// What to do when the prompt plus `max_new_tokens` exceeds the context length
+    pub context_overflow: ContextOverflow,
+}
+
+/// Tokens and text produced by one call of the generation loop
+#[derive(Debug, Clone)]
+pub(crate) struct GenerationOutput {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub logprobs: Option<FastVLMLogprobs>,
+    pub completion_tokens: usize,
+}
+
+impl From<GenerationOutput> for FastVLMCandidate {
+    fn from(output: GenerationOutput) -> Self {
+        Self {
+            text: output.text,
+            finish_reason: output.finish_reason,
+            logprobs: output.logprobs,
+            completion_tokens: output.completion_tokens,
+        }
+    }
+}
+
+impl GenerationParams {
+    pub fn is_stop_token(&self, token_id: i64) -> bool {
+        self.stop_token_ids.iter().any(|&stop| stop as i64 == token_id)
+    }
+
+    /// Apply the penalties and the user processors to one step's logits
+    pub fn process_logits(&self, generated: &[u32], logits: &mut Array1<f32>) {
+        let Some(logits) = logits.as_slice_mut() else { return };
+        let processors = std::iter::once(&self.penalties as &dyn LogitsProcessor)
+            .chain(self.logits_processors.iter().map(|processor| processor.as_ref()));
+        for processor in processors {
+            processor.process(generated, logits);
+        }
+    }
+
+    /// Whether any user stopping criterion is met
+    pub fn should_stop(&self, generated: &[u32], text: &str) -> bool {
+        self.stopping_criteria.iter().any(|criteria| criteria.should_stop(generated, text))
+    }
+
+    /// Sampling parameters actually used by the token picker for this strategy
+    pub fn effective_sampling(&self) -> SamplingParams {
+        match self.strategy {
+            DecodingStrategy::Sample => self.sampling,
+            DecodingStrategy::Greedy | DecodingStrategy::Beam { .. } => SamplingParams {
+                temperature: 0.0,
+                ..self.sampling
+            },
+        }
+    }
+}
+
+/// Decoder input of a request: embeddings of the prompt positions not covered by `past`
+#[derive(Clone)]
+pub(crate) struct PromptInput {
+    pub input_embeds: Array3<f32>,
+    pub past: KvCache,
+}
+
+/// In-flight sampling generation, advanced one token at a time
+///
+/// Cloning a state right after the prefill forks the KV cache, so several
+/// continuations can share one pass over the image and prompt.
+#[derive(Clone)]
+pub(crate) struct GenerationState {
+    pub params: GenerationParams,
+    pub sampling: SamplingParams,
+    pub tokens: Vec<u32>,
+    /// One record per entry of `tokens` when logprobs are requested
+    pub logprobs: Vec<StepLogprobs>,
+    /// Embeddings fed to the decoder on the next step
+    pub inputs_embeds: Array3<f32>,
+    pub past_key_values: KvCache,
+    /// Logits of a decoder pass already run, used by the next step instead of `inputs_embeds`
+    pub pending_logits: Option<Array1<f32>>,
+    pub finish_reason: Option<FinishReason>,
+    pub deltas: TextDeltaDecoder,
+}
+
+impl GenerationState {
+    pub fn new(inputs_embeds: Array3<f32>, params: GenerationParams, past_key_values: KvCache) -> Self {
+        Self {
+            sampling: params.effective_sampling(),
+            tokens: Vec::with_capacity(params.max_new_tokens),
+            logprobs: Vec::new(),
+            params,
+            inputs_embeds,
+            past_key_values,
+            pending_logits: None,
+            finish_reason: None,
+            deltas: TextDeltaDecoder::default(),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finish_reason.is_some()
+    }
+
+    /// Mark the generation as finished, keeping the first reason recorded
+    pub fn finish(&mut self, finish_reason: FinishReason) {
+        self.finish_reason.get_or_insert(finish_reason);
+    }
+
+    /// Append a sampled token, dropping the repeated tail and finishing when it closes a loop
+    ///
+    /// Returns whether a loop was detected.
+    pub fn push_token(&mut self, token: u32, logprobs: Option<StepLogprobs>) -> bool {
+        self.tokens.push(token);
+        self.logprobs.extend(logprobs);
+        let Some(keep) = self.params.loop_detection.and_then(|detector| detector.detect(&self.tokens)) else {
+            return false;
+        };
+        self.tokens.truncate(keep);
+        self.logprobs.truncate(keep);
+        self.finish(FinishReason::Repetition);
+        true
+    }
+
+    /// Tokens whose text can be streamed, loop detection may still remove the ones after them
+    pub fn streamable_tokens(&self) -> &[u32] {
+        let held_back = match self.params.loop_detection {
+            Some(detector) if !self.is_finished() => detector.max_removed().min(self.tokens.len()),
+            _ => 0,
+        };
+        &self.tokens[..self.tokens.len() - held_back]
+    }
+}
+
+/// Byte offset of the earliest stop sequence in `text`
+///
+/// The whole decoded text is searched so matches spanning token boundaries are found.
+pub fn find_stop_sequence(text: &str, stop_sequences: &[String]) -> Option<usize> {
+    stop_sequences.iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| text.find(stop.as_str()))
+        .min()
+}
+
+/// Decoder past_key_values, stored as `[key_0, value_0, key_1, value_1, ...]`
+#[derive(Clone)]
+pub(crate) struct KvCache {
+    tensors: Vec<Array4<f32>>,
+}
+
+impl KvCache {
+    /// Cache with zero cached positions, used for the first (prefill) pass
+    pub fn empty(num_layers: usize, num_kv_heads: usize, head_dim: usize) -> Self {
+        Self {
+            tensors: (0..num_layers * 2)
+                .map(|_| Array4::<f32>::zeros((1, num_kv_heads, 0, head_dim)))
+                .collect(),
+        }
+    }
+
+    pub fn from_tensors(tensors: Vec<Array4<f32>>) -> Self {
+        Self { tensors }
+    }
+
+    pub fn tensors(&self) -> &[Array4<f32>] {
+        &self.tensors
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.tensors.len() / 2
+    }
+
+    /// Number of cached positions
+    pub fn seq_len(&self) -> usize {
+        self.tensors.first().map(|tensor| tensor.shape()[2]).unwrap_or(0)
+    }
+
+    /// The first `len` cached positions
+    pub fn prefix(&self, len: usize) -> Self {
+        Self {
+            tensors: self.tensors.iter()
+                .map(|tensor| tensor.slice(s![.., .., ..len.min(tensor.shape()[2]), ..]).to_owned())
+                .collect(),
+        }
+    }
+
+    pub fn key(&self, layer: usize) -> &Array4<f32> {
+        &self.tensors[layer * 2]
+    }
+
+    pub fn value(&self, layer: usize) -> &Array4<f32> {
+        &self.tensors[layer * 2 + 1]
+    }
+}
+
+/// Numerically stable log-softmax over a logits row
+pub fn log_softmax(logits: &ArrayView1<f32>) -> Vec<f32> {
+    let max_logit = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum_exp = logits.iter().map(|&logit| (logit - max_logit).exp()).sum::<f32>().ln() + max_logit;
+    logits.iter().map(|&logit| logit - log_sum_exp).collect()
+}
+
+/// Indices of the `k` largest values, in descending order
+pub fn top_k_indices(values: &[f32], k: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..values.len()).filter(|&i| !values[i].is_nan()).collect();
+    indices.sort_by(|&a, &b| values[b].partial_cmp(&values[a]).unwrap_or(std::cmp::Ordering::Equal));
+    indices.truncate(k);
+    indices
+}
+
+/// Finished beam-search hypotheses, keeping the best `num_beams` by normalized score
+pub(crate) struct BeamHypotheses {
+    num_beams: usize,
+    length_penalty: f32,
+    early_stopping: bool,
+    hypotheses: Vec<(f32, Vec<u32>, Vec<StepLogprobs>, FinishReason)>,
+}
+
+impl BeamHypotheses {
+    pub fn new(num_beams: usize, length_penalty: f32, early_stopping: bool) -> Self {
+        Self {
+            num_beams,
+            length_penalty,
+            early_stopping,
+            hypotheses: Vec::with_capacity(num_beams + 1),
+        }
+    }
+
+    fn normalized(&self, sum_logprobs: f32, length: usize) -> f32 {
+        sum_logprobs / (length.max(1) as f32).powf(self.length_penalty)
+    }
+
+    fn worst_score(&self) -> f32 {
+        self.hypotheses.iter().map(|(score, ..)| *score).fold(f32::INFINITY, f32::min)
+    }
+
+    pub fn add(&mut self, tokens: Vec<u32>, logprobs: Vec<StepLogprobs>, sum_logprobs: f32, finish_reason: FinishReason) {
+        let score = self.normalized(sum_logprobs, tokens.len());
+        if self.hypotheses.len() < self.num_beams || score > self.worst_score() {
+            self.hypotheses.push((score, tokens, logprobs, finish_reason));
+            if self.hypotheses.len() > self.num_beams {
+                let worst = self.hypotheses.iter()
+                    .enumerate()
+                    .min_by(|a, b| a.1.0.partial_cmp(&b.1.0).unwrap_or(std::cmp::Ordering::Equal))
+                    .map(|(i, _)| i)
+                    .unwrap();
+                self.hypotheses.remove(worst);
+            }
+        }
+    }
+
+    /// Whether no live beam can still beat the finished hypotheses
+    pub fn is_done(&self, best_live_sum_logprobs: f32, live_length: usize) -> bool {
+        if self.hypotheses.len() < self.num_beams {
+            return false;
+        }
+        if self.early_stopping {
+            return true;
+        }
+        self.normalized(best_live_sum_logprobs, live_length) <= self.worst_score()
+    }
+
+    pub fn best(self) -> Option<(Vec<u32>, Vec<StepLogprobs>, FinishReason)> {
+        self.hypotheses.into_iter()
+            .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(_, tokens, logprobs, finish_reason)| (tokens, logprobs, finish_reason))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastvlm::fastvlm_chat::expand_image_placeholders;
+    use ndarray::array;
+
+    #[test]
+    fn test_usage_total_is_decoder_length() {
+        // Two images of 3 and 2 rows replace their placeholders among 4 text tokens
+        let (ids, _) = expand_image_placeholders(&[1, 99, 2, 99, 3, 4], 99, &[3, 2]).unwrap();
+        let usage = FastVLMUsage { completion_tokens: 7, ..FastVLMUsage::for_prompt(ids.len(), 5, None) };
+        assert_eq!(usage.prompt_tokens, 4);
+        assert_eq!(usage.total_tokens(), ids.len() + 7);
+    }
+
+    #[test]
+    fn test_log_softmax_sums_to_one() {
+        let logits = array![2.0, 1.0, 0.1];
+        let total: f32 = log_softmax(&logits.view()).iter().map(|lp| lp.exp()).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_step_logprobs_records_top_alternatives() {
+        let logits = array![0.0, 2.0, 1.0];
+        let record = StepLogprobs::record(&logits.view(), 2, 2);
+        assert_eq!(record.top.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(record.logprob, record.top[1].1);
+        assert!(record.logprob < 0.0);
+    }
+
+    #[test]
+    fn test_beam_hypotheses_keep_best() {
+        let mut hypotheses = BeamHypotheses::new(2, 1.0, false);
+        hypotheses.add(vec![1, 2], Vec::new(), -4.0, FinishReason::Eos);
+        hypotheses.add(vec![3], Vec::new(), -1.0, FinishReason::Eos);
+        hypotheses.add(vec![4, 5], Vec::new(), -1.0, FinishReason::MaxLength);
+        // -1.0 / 2 beats -1.0 / 1 once normalized by length
+        assert!(hypotheses.is_done(-3.0, 2));
+        assert!(!hypotheses.is_done(-0.5, 2));
+        let (tokens, _, finish_reason) = hypotheses.best().unwrap();
+        assert_eq!((tokens, finish_reason), (vec![4, 5], FinishReason::MaxLength));
+    }
+
+    #[test]
+    fn test_find_stop_sequence_earliest_match() {
+        let stops = vec!["\n\n".to_string(), ".".to_string()];
+        assert_eq!(find_stop_sequence("A red car. It is parked", &stops), Some(9));
+        assert_eq!(find_stop_sequence("A red car\n\nparked.", &stops), Some(9));
+        assert_eq!(find_stop_sequence("A red car", &stops), None);
+    }
+
+    #[test]
+    fn test_loop_detection_keeps_one_cycle() {
+        let detector = LoopDetection { max_ngram_size: 4, min_repeats: 3 };
+        // "a cat , a cat , a cat ,"
+        let tokens = [7, 1, 2, 3, 1, 2, 3, 1, 2, 3];
+        assert_eq!(detector.detect(&tokens), Some(4));
+        assert_eq!(detector.detect(&tokens[..9]), None);
+        assert_eq!(detector.detect(&[5, 5, 5]), Some(1));
+    }
+}
@@ -1,9 +1,12 @@
 use anyhow::Result;
 use image::{DynamicImage, GenericImageView};
 use ndarray::Array4;
+use serde_json::Value;
+use std::hash::{Hash, Hasher};
 
 /// FastVLM Image Processor following CLIP-style preprocessing
-/// Based on config: 1024x1024 center crop, rescale factor 0.00392156862745098 (1/255)
+/// Defaults to a 1024x1024 crop and rescale factor 0.00392156862745098 (1/255),
+/// overridden by the model's `preprocessor_config.json`
 pub struct FastVLMImageProcessor {
     crop_size: (u32, u32),
     image_mean: Vec<f32>,
@@ -22,12 +25,58 @@ impl FastVLMImageProcessor {
         }
     }
 
+    /// Build the processor from `preprocessor_config.json`, keeping the defaults of `new` for missing keys
+    pub fn from_preprocessor_config(config: &Value) -> Self {
+        let mut processor = Self::new();
+        let size = |value: &Value| -> Option<(u32, u32)> {
+            if let Some(side) = value.as_u64() {
+                return Some((side as u32, side as u32));
+            }
+            let dim = |key: &str| value.get(key).and_then(Value::as_u64).map(|v| v as u32);
+            dim("shortest_edge").map(|side| (side, side))
+                .or_else(|| Some((dim("width")?, dim("height")?)))
+        };
+        let channels = |key: &str| -> Option<Vec<f32>> {
+            let values: Vec<f32> = config.get(key)?.as_array()?.iter().filter_map(Value::as_f64).map(|v| v as f32).collect();
+            (values.len() == 3).then_some(values)
+        };
+
+        if let Some(crop_size) = config.get("crop_size").or_else(|| config.get("size")).and_then(size) {
+            processor.crop_size = crop_size;
+        }
+        if let Some(mean) = channels("image_mean") {
+            processor.image_mean = mean;
+        }
+        if let Some(std) = channels("image_std") {
+            processor.image_std = std;
+        }
+        if let Some(factor) = config.get("rescale_factor").and_then(Value::as_f64) {
+            processor.rescale_factor = factor as f32;
+        }
+        if config.get("do_normalize").and_then(Value::as_bool) == Some(false) {
+            processor.image_mean = vec![0.0; 3];
+            processor.image_std = vec![1.0; 3];
+        }
+        if config.get("do_rescale").and_then(Value::as_bool) == Some(false) {
+            processor.rescale_factor = 1.0;
+        }
+        processor
+    }
+
+    /// Feed the settings that affect `preprocess` output into `state`
+    pub(crate) fn hash_settings<H: Hasher>(&self, state: &mut H) {
+        self.crop_size.hash(state);
+        for value in self.image_mean.iter().chain(&self.image_std).chain([&self.rescale_factor]) {
+            value.to_bits().hash(state);
+        }
+    }
+
     /// Main preprocessing function for FastVLM
     pub fn preprocess(&self, image: &DynamicImage) -> Result<FastVLMBatchFeature> {
         // Convert to RGB if needed
         let rgb_image = DynamicImage::ImageRgb8(image.to_rgb8());
         
-        // Resize with padding to 1024x1024 (preserves entire image content)
+        // Resize with padding to the crop size (preserves entire image content)
         let processed = self.resize_with_padding(&rgb_image, self.crop_size.0, self.crop_size.1)?;
         
         // Convert to tensor and normalize
@@ -68,7 +117,7 @@ impl FastVLMImageProcessor {
 
 
     /// Convert image to tensor format expected by FastVLM
-    /// Output shape: [1, 3, 1024, 1024] (batch, channels, height, width)
+    /// Output shape: [1, 3, crop_height, crop_width] (batch, channels, height, width)
     fn to_tensor(&self, image: &DynamicImage) -> Result<Array4<f32>> {
         let (width, height) = image.dimensions();
         
@@ -95,4 +144,26 @@ impl Default for FastVLMImageProcessor {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_preprocessor_config() {
+        let processor = FastVLMImageProcessor::from_preprocessor_config(&serde_json::json!({
+            "crop_size": { "height": 512, "width": 768 },
+            "image_mean": [0.5, 0.5, 0.5],
+            "image_std": [0.25, 0.25, 0.25],
+            "do_rescale": false,
+        }));
+        assert_eq!(processor.crop_size, (768, 512));
+        assert_eq!(processor.image_mean, vec![0.5; 3]);
+        assert_eq!(processor.rescale_factor, 1.0);
+
+        let processor = FastVLMImageProcessor::from_preprocessor_config(&serde_json::json!({ "size": { "shortest_edge": 336 } }));
+        assert_eq!(processor.crop_size, (336, 336));
+        assert_eq!(processor.image_std, vec![1.0; 3]);
+    }
+}
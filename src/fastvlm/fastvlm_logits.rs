@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+/// Modifies the next-token logits before a token is picked
+///
+/// Processors run on the last-position logits of every decoding step, after the
+/// built-in penalties and before the sampling warpers (temperature, top-k, top-p,
+/// min-p). Set a logit to `f32::NEG_INFINITY` to forbid a token. Processors must
+/// derive everything from `generated`, since one processor instance is shared by
+/// all beams and candidates of a request.
+///
+/// Closures `Fn(&[u32], &mut [f32])` implement this trait.
+pub trait LogitsProcessor: Send + Sync {
+    /// `generated` holds the tokens produced so far, without the prompt
+    fn process(&self, generated: &[u32], logits: &mut [f32]);
+
+    /// Name shown in debug output
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Decides whether generation should stop after the latest token
+///
+/// Checked after every generated token. `text` is the decoded text so far.
+/// Stopping this way reports `FinishReason::StoppingCriteria`.
+///
+/// Closures `Fn(&[u32], &str) -> bool` implement this trait.
+pub trait StoppingCriteria: Send + Sync {
+    fn should_stop(&self, generated: &[u32], text: &str) -> bool;
+
+    /// Name shown in debug output
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+impl<F> LogitsProcessor for F
+where
+    F: Fn(&[u32], &mut [f32]) + Send + Sync,
+{
+    fn process(&self, generated: &[u32], logits: &mut [f32]) {
+        self(generated, logits)
+    }
+}
+
+impl<F> StoppingCriteria for F
+where
+    F: Fn(&[u32], &str) -> bool + Send + Sync,
+{
+    fn should_stop(&self, generated: &[u32], text: &str) -> bool {
+        self(generated, text)
+    }
+}
+
+impl std::fmt::Debug for dyn LogitsProcessor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::fmt::Debug for dyn StoppingCriteria {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Divide the logits by the temperature, which must be > 0
+#[derive(Debug, Clone, Copy)]
+pub struct TemperatureLogitsWarper(pub f32);
+
+impl LogitsProcessor for TemperatureLogitsWarper {
+    fn process(&self, _generated: &[u32], logits: &mut [f32]) {
+        if self.0 != 1.0 {
+            logits.iter_mut().for_each(|logit| *logit /= self.0);
+        }
+    }
+}
+
+/// Keep only the k most likely tokens, `0` disables the filter
+#[derive(Debug, Clone, Copy)]
+pub struct TopKLogitsWarper(pub usize);
+
+impl LogitsProcessor for TopKLogitsWarper {
+    fn process(&self, _generated: &[u32], logits: &mut [f32]) {
+        if self.0 == 0 {
+            return;
+        }
+        let ranked = ranked_indices(logits);
+        for &idx in ranked.iter().skip(self.0) {
+            logits[idx] = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Keep the smallest set of most likely tokens whose probability mass reaches `top_p`
+#[derive(Debug, Clone, Copy)]
+pub struct TopPLogitsWarper(pub f32);
+
+impl LogitsProcessor for TopPLogitsWarper {
+    fn process(&self, _generated: &[u32], logits: &mut [f32]) {
+        if self.0 >= 1.0 {
+            return;
+        }
+        let probabilities = softmax(logits);
+        let ranked = ranked_indices(logits);
+        let mut cumulative_prob = 0.0;
+        let mut keep = ranked.len();
+        for (i, &idx) in ranked.iter().enumerate() {
+            cumulative_prob += probabilities[idx];
+            if cumulative_prob >= self.0 {
+                keep = i + 1;
+                break;
+            }
+        }
+        for &idx in &ranked[keep..] {
+            logits[idx] = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Drop tokens whose probability is below `min_p` times the most likely one, `0.0` disables the filter
+#[derive(Debug, Clone, Copy)]
+pub struct MinPLogitsWarper(pub f32);
+
+impl LogitsProcessor for MinPLogitsWarper {
+    fn process(&self, _generated: &[u32], logits: &mut [f32]) {
+        if self.0 <= 0.0 {
+            return;
+        }
+        let probabilities = softmax(logits);
+        let threshold = probabilities.iter().cloned().fold(0.0, f32::max) * self.0;
+        for (logit, prob) in logits.iter_mut().zip(probabilities) {
+            if prob < threshold {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+/// Never generate the given token ids
+#[derive(Debug, Clone, Default)]
+pub struct SuppressTokensLogitsProcessor(pub Vec<u32>);
+
+impl LogitsProcessor for SuppressTokensLogitsProcessor {
+    fn process(&self, _generated: &[u32], logits: &mut [f32]) {
+        for &token in &self.0 {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+/// Add a fixed bias to the logits of the given token ids
+#[derive(Debug, Clone, Default)]
+pub struct LogitBiasProcessor(pub HashMap<u32, f32>);
+
+impl LogitsProcessor for LogitBiasProcessor {
+    fn process(&self, _generated: &[u32], logits: &mut [f32]) {
+        for (&token, &bias) in &self.0 {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit += bias;
+            }
+        }
+    }
+}
+
+/// Indices of the finite logits, most likely first
+pub(crate) fn ranked_indices(logits: &[f32]) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..logits.len())
+        .filter(|&i| !logits[i].is_nan() && logits[i] != f32::NEG_INFINITY)
+        .collect();
+    indices.sort_by(|&a, &b| logits[b].partial_cmp(&logits[a]).unwrap_or(std::cmp::Ordering::Equal));
+    indices
+}
+
+/// Softmax over the finite logits, masked and NaN entries get probability 0
+pub(crate) fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max_logit = logits.iter().cloned().filter(|logit| !logit.is_nan()).fold(f32::NEG_INFINITY, f32::max);
+    if max_logit == f32::NEG_INFINITY {
+        return vec![0.0; logits.len()];
+    }
+    let weights: Vec<f32> = logits.iter()
+        .map(|&logit| if logit.is_nan() { 0.0 } else { (logit - max_logit).exp() })
+        .collect();
+    let sum: f32 = weights.iter().sum();
+    weights.into_iter().map(|weight| weight / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_warpers_mask_tokens() {
+        let mut logits = vec![4.0, 3.0, 2.0, 1.0, 0.0];
+        TopKLogitsWarper(3).process(&[], &mut logits);
+        assert_eq!(&logits[3..], &[f32::NEG_INFINITY, f32::NEG_INFINITY]);
+
+        let mut logits = vec![4.0, 3.0, 2.0, 1.0, 0.0];
+        MinPLogitsWarper(0.3).process(&[], &mut logits);
+        assert_eq!(logits[..2], [4.0, 3.0]);
+        assert!(logits[2..].iter().all(|&logit| logit == f32::NEG_INFINITY));
+    }
+
+    #[test]
+    fn test_closures_as_processors() {
+        let ban_after_first: Arc<dyn LogitsProcessor> = Arc::new(|generated: &[u32], logits: &mut [f32]| {
+            if !generated.is_empty() {
+                logits[0] = f32::NEG_INFINITY;
+            }
+        });
+        let mut logits = vec![1.0, 2.0];
+        ban_after_first.process(&[], &mut logits);
+        assert_eq!(logits[0], 1.0);
+        ban_after_first.process(&[7], &mut logits);
+        assert_eq!(logits[0], f32::NEG_INFINITY);
+
+        let two_sentences: Arc<dyn StoppingCriteria> = Arc::new(|_: &[u32], text: &str| text.matches('.').count() >= 2);
+        assert!(!two_sentences.should_stop(&[], "A cat."));
+        assert!(two_sentences.should_stop(&[], "A cat. On a mat."));
+    }
+}
@@ -0,0 +1,259 @@
+use std::path::Path;
+
+use anyhow::Result;
+use serde_json::Value;
+
+use super::fastvlm::FastVLMConfig;
+use super::fastvlm_generation::DecodingStrategy;
+
+// Fallback special tokens of the FastVLM (Qwen2) tokenizer
+pub(crate) const ENDOFTEXT_TOKEN_ID: u32 = 151643; // <|endoftext|>
+pub(crate) const IM_END_TOKEN_ID: u32 = 151645; // <|im_end|>
+pub(crate) const IMAGE_TOKEN_ID: u32 = 151646; // <image>
+
+/// Hugging Face config files found in a model directory
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ModelConfigFiles {
+    pub config: Option<Value>,
+    pub generation_config: Option<Value>,
+    pub preprocessor_config: Option<Value>,
+    pub tokenizer_config: Option<Value>,
+}
+
+/// Sampling defaults from `generation_config.json`, `None` where the file has no value
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct GenerationDefaults {
+    pub do_sample: Option<bool>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+}
+
+/// Token ids and generation defaults of the loaded model
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ModelDefaults {
+    /// Tokens that end generation
+    pub eos_token_ids: Vec<u32>,
+    /// Token closing an assistant turn in the chat template
+    pub end_of_turn_token_id: u32,
+    /// Placeholder replaced by the image features
+    pub image_token_id: u32,
+    /// Logits past this index belong to padding rows of the embedding matrix and are ignored
+    pub vocab_size: usize,
+    /// `max_position_embeddings` of the language model, `None` when `config.json` has none
+    pub context_length: Option<usize>,
+    pub generation: GenerationDefaults,
+}
+
+impl ModelConfigFiles {
+    /// Read the config files present in `data_dir`
+    pub fn read(data_dir: &Path) -> Result<Self> {
+        Ok(Self {
+            config: read_json(data_dir, "config.json")?,
+            generation_config: read_json(data_dir, "generation_config.json")?,
+            preprocessor_config: read_json(data_dir, "preprocessor_config.json")?,
+            tokenizer_config: read_json(data_dir, "tokenizer_config.json")?,
+        })
+    }
+
+    /// `chat_template` of `tokenizer_config.json`, picking the `default` entry when several are given
+    pub fn chat_template(&self) -> Option<&str> {
+        let template = self.tokenizer_config.as_ref()?.get("chat_template")?;
+        template.as_str().or_else(|| {
+            let templates = template.as_array()?;
+            templates.iter()
+                .find(|entry| entry.get("name").and_then(Value::as_str) == Some("default"))
+                .or_else(|| templates.first())?
+                .get("template")?
+                .as_str()
+        })
+    }
+
+    /// Content of a special token declared in `tokenizer_config.json`, e.g. `bos_token`
+    pub fn special_token(&self, key: &str) -> Option<&str> {
+        self.tokenizer_config.as_ref()?.get(key).and_then(token_content)
+    }
+}
+
+impl ModelDefaults {
+    /// Resolve token ids from the config files, falling back to the tokenizer and
+    /// then to the built-in Qwen2 ids
+    pub fn resolve(files: &ModelConfigFiles, token_to_id: impl Fn(&str) -> Option<u32>, vocab_size: usize) -> Self {
+        let end_of_turn_token_id = files.special_token("eos_token")
+            .and_then(&token_to_id)
+            .or_else(|| token_to_id("<|im_end|>"))
+            .unwrap_or(IM_END_TOKEN_ID);
+
+        let mut eos_token_ids = [&files.generation_config, &files.config].into_iter()
+            .find_map(|config| config.as_ref()?.get("eos_token_id").map(token_ids))
+            .filter(|ids| !ids.is_empty())
+            .unwrap_or_else(|| vec![IM_END_TOKEN_ID, ENDOFTEXT_TOKEN_ID]);
+        if !eos_token_ids.contains(&end_of_turn_token_id) {
+            eos_token_ids.insert(0, end_of_turn_token_id);
+        }
+
+        let image_token_id = files.config.as_ref()
+            .and_then(|config| ["image_token_index", "image_token_id"].iter().find_map(|key| config.get(*key)?.as_u64()))
+            .map(|id| id as u32)
+            .or_else(|| token_to_id("<image>"))
+            .unwrap_or(IMAGE_TOKEN_ID);
+
+        let context_length = files.config.as_ref()
+            .map(|config| config.get("text_config").unwrap_or(config))
+            .and_then(|config| config.get("max_position_embeddings")?.as_u64())
+            .map(|length| length as usize);
+
+        let generation = files.generation_config.as_ref()
+            .map(|config| {
+                let float = |key: &str| config.get(key).and_then(Value::as_f64).map(|v| v as f32);
+                GenerationDefaults {
+                    do_sample: config.get("do_sample").and_then(Value::as_bool),
+                    temperature: float("temperature"),
+                    top_k: config.get("top_k").and_then(Value::as_u64).map(|v| v as usize),
+                    top_p: float("top_p"),
+                    repetition_penalty: float("repetition_penalty"),
+                }
+            })
+            .unwrap_or_default();
+
+        Self {
+            eos_token_ids,
+            end_of_turn_token_id,
+            image_token_id,
+            vocab_size: if vocab_size > 0 { vocab_size } else { IMAGE_TOKEN_ID as usize + 1 },
+            context_length,
+            generation,
+        }
+    }
+
+    /// Fill the fields of `config` left unset with the model's defaults
+    ///
+    /// Fields the model has no value for stay `None` and fall back to the built-in
+    /// defaults when the generation parameters are built.
+    pub fn apply(&self, mut config: FastVLMConfig) -> FastVLMConfig {
+        let generation = &self.generation;
+
+        if config.stop_token_ids.is_none() {
+            config.stop_token_ids = Some(self.eos_token_ids.clone());
+        }
+        if config.decoding_strategy.is_none() && generation.do_sample == Some(false) {
+            config.decoding_strategy = Some(DecodingStrategy::Greedy);
+        }
+        config.temperature = config.temperature.or(generation.temperature);
+        config.top_k = config.top_k.or(generation.top_k);
+        config.top_p = config.top_p.or(generation.top_p);
+        config.repetition_penalty = config.repetition_penalty.or(generation.repetition_penalty);
+        if config.max_context_length.is_none() {
+            config.max_context_length = self.context_length;
+        }
+        config
+    }
+}
+
+/// Parse `name` from the model directory, if present
+pub(crate) fn read_json(data_dir: &Path, name: &str) -> Result<Option<Value>> {
+    let path = data_dir.join(name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read_to_string(&path)?;
+    let value = serde_json::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Invalid {}: {}", path.display(), e))?;
+    Ok(Some(value))
+}
+
+/// `"<|im_end|>"` or `{"content": "<|im_end|>", ...}` as found in `tokenizer_config.json`
+fn token_content(token: &Value) -> Option<&str> {
+    token.as_str().or_else(|| token.get("content")?.as_str())
+}
+
+/// A single id or a list of ids
+fn token_ids(value: &Value) -> Vec<u32> {
+    match value {
+        Value::Array(ids) => ids.iter().filter_map(Value::as_u64).map(|id| id as u32).collect(),
+        value => value.as_u64().map(|id| vec![id as u32]).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastvlm::fastvlm::FastVLMRequestOptions;
+    use crate::fastvlm::fastvlm_sampling::SamplingParams;
+    use serde_json::json;
+
+    fn qwen_token_to_id(token: &str) -> Option<u32> {
+        match token {
+            "<|endoftext|>" => Some(151643),
+            "<|im_end|>" => Some(151645),
+            "<image>" => Some(151646),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_defaults_from_config_files() {
+        let files = ModelConfigFiles {
+            config: Some(json!({
+                "image_token_index": 151646,
+                "eos_token_id": 151645,
+                "text_config": { "max_position_embeddings": 32768 },
+            })),
+            generation_config: Some(json!({ "eos_token_id": [151643], "do_sample": false, "temperature": 0.2, "top_k": 20 })),
+            tokenizer_config: Some(json!({ "eos_token": { "content": "<|im_end|>", "special": true } })),
+            ..Default::default()
+        };
+        let defaults = ModelDefaults::resolve(&files, qwen_token_to_id, 151647);
+        assert_eq!(defaults.eos_token_ids, vec![151645, 151643]);
+        assert_eq!(defaults.end_of_turn_token_id, 151645);
+        assert_eq!(defaults.image_token_id, 151646);
+        assert_eq!(files.chat_template(), None);
+
+        let config = defaults.apply(FastVLMConfig { top_k: Some(5), ..Default::default() });
+        assert_eq!(config.decoding_strategy, Some(DecodingStrategy::Greedy));
+        assert_eq!(config.temperature, Some(0.2));
+        assert_eq!(config.top_k, Some(5));
+        assert_eq!(config.top_p, None);
+        assert_eq!(config.stop_token_ids, Some(vec![151645, 151643]));
+        assert_eq!(config.max_context_length, Some(32768));
+        let config = defaults.apply(FastVLMConfig { max_context_length: Some(4096), ..Default::default() });
+        assert_eq!(config.max_context_length, Some(4096));
+    }
+
+    #[test]
+    fn test_fallback_without_config_files() {
+        let defaults = ModelDefaults::resolve(&ModelConfigFiles::default(), |_| None, 0);
+        assert_eq!(defaults.eos_token_ids, vec![IM_END_TOKEN_ID, ENDOFTEXT_TOKEN_ID]);
+        assert_eq!(defaults.image_token_id, IMAGE_TOKEN_ID);
+        assert_eq!(defaults.vocab_size, 151647);
+        assert_eq!(defaults.context_length, None);
+
+        let config = defaults.apply(FastVLMConfig::default());
+        let options = FastVLMRequestOptions::default();
+        assert_eq!(config.sampling_params(&options), SamplingParams::default());
+        assert_eq!(config.generation_params(&options).unwrap().strategy, DecodingStrategy::Sample);
+    }
+
+    #[test]
+    fn test_explicit_builtin_values_survive_apply() {
+        let files = ModelConfigFiles {
+            generation_config: Some(json!({ "eos_token_id": [7], "do_sample": false, "temperature": 0.2, "top_k": 20, "top_p": 0.8 })),
+            ..Default::default()
+        };
+        let defaults = ModelDefaults::resolve(&files, qwen_token_to_id, 151647);
+        let builtin = SamplingParams::default();
+        let explicit = FastVLMConfig {
+            temperature: Some(builtin.temperature),
+            top_k: Some(builtin.top_k),
+            top_p: Some(builtin.top_p),
+            decoding_strategy: Some(DecodingStrategy::Sample),
+            stop_token_ids: Some(vec![IM_END_TOKEN_ID, ENDOFTEXT_TOKEN_ID]),
+            ..Default::default()
+        };
+        let config = defaults.apply(explicit);
+        assert_eq!(config.sampling_params(&FastVLMRequestOptions::default()), builtin);
+        assert_eq!(config.decoding_strategy, Some(DecodingStrategy::Sample));
+        assert_eq!(config.stop_token_ids, Some(vec![IM_END_TOKEN_ID, ENDOFTEXT_TOKEN_ID]));
+    }
+}
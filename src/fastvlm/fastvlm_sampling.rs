@@ -0,0 +1,261 @@
+use anyhow::Result;
+use ndarray::{ArrayView1, ArrayViewMut1};
+use std::collections::HashMap;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+
+use super::fastvlm_logits::{
+    ranked_indices, softmax, LogitsProcessor, MinPLogitsWarper, TemperatureLogitsWarper, TopKLogitsWarper, TopPLogitsWarper,
+};
+
+/// Sampling parameters for one generation request
+///
+/// Resolved from `FastVLMConfig` and overridden by `FastVLMRequestOptions`.
+/// Filters are applied in a fixed order: temperature -> top-k -> top-p -> min-p.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingParams {
+    /// Softmax temperature, `0.0` means greedy (argmax)
+    pub temperature: f32,
+    /// Keep only the k most likely tokens, `0` disables the filter
+    pub top_k: usize,
+    /// Nucleus sampling threshold in (0, 1], `1.0` disables the filter
+    pub top_p: f32,
+    /// Drop tokens whose probability is below `min_p * max_prob`, `0.0` disables the filter
+    pub min_p: f32,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: 0.7,
+            top_k: 50,
+            top_p: 1.0,
+            min_p: 0.0,
+        }
+    }
+}
+
+impl SamplingParams {
+    pub fn validate(&self) -> Result<()> {
+        if !self.temperature.is_finite() || self.temperature < 0.0 {
+            return Err(anyhow::anyhow!("temperature must be a finite value >= 0, got {}", self.temperature));
+        }
+        if !(self.top_p > 0.0 && self.top_p <= 1.0) {
+            return Err(anyhow::anyhow!("top_p must be in (0, 1], got {}", self.top_p));
+        }
+        if !(0.0..=1.0).contains(&self.min_p) {
+            return Err(anyhow::anyhow!("min_p must be in [0, 1], got {}", self.min_p));
+        }
+        Ok(())
+    }
+
+    /// Whether sampling degenerates to picking the most likely token
+    pub fn is_greedy(&self) -> bool {
+        self.temperature == 0.0 || self.top_k == 1
+    }
+
+    /// The sampling filters as logits warpers, in the order they are applied
+    pub fn warpers(&self) -> [Box<dyn LogitsProcessor>; 4] {
+        [
+            Box::new(TemperatureLogitsWarper(self.temperature)),
+            Box::new(TopKLogitsWarper(self.top_k)),
+            Box::new(TopPLogitsWarper(self.top_p)),
+            Box::new(MinPLogitsWarper(self.min_p)),
+        ]
+    }
+}
+
+/// Penalties applied to the logits based on the tokens generated so far
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PenaltyParams {
+    /// Multiplicative penalty for already generated tokens (HF/CTRL style), `1.0` disables it
+    pub repetition_penalty: f32,
+    /// Subtracted once per previous occurrence of a token, `0.0` disables it
+    pub frequency_penalty: f32,
+    /// Subtracted once if a token occurred at all, `0.0` disables it
+    pub presence_penalty: f32,
+    /// Forbid repeating any n-gram of this size, `0` disables it
+    pub no_repeat_ngram_size: usize,
+}
+
+impl Default for PenaltyParams {
+    fn default() -> Self {
+        Self {
+            repetition_penalty: 1.0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            no_repeat_ngram_size: 0,
+        }
+    }
+}
+
+impl PenaltyParams {
+    pub fn validate(&self) -> Result<()> {
+        if !self.repetition_penalty.is_finite() || self.repetition_penalty <= 0.0 {
+            return Err(anyhow::anyhow!("repetition_penalty must be a finite value > 0, got {}", self.repetition_penalty));
+        }
+        if !self.frequency_penalty.is_finite() || !self.presence_penalty.is_finite() {
+            return Err(anyhow::anyhow!("frequency_penalty and presence_penalty must be finite"));
+        }
+        Ok(())
+    }
+
+    pub fn is_noop(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply all penalties in place, `generated` being the tokens produced so far
+    pub fn apply(&self, mut logits: ArrayViewMut1<f32>, generated: &[u32]) {
+        if self.is_noop() || generated.is_empty() {
+            return;
+        }
+
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for &token in generated {
+            *counts.entry(token).or_insert(0) += 1;
+        }
+
+        for (&token, &count) in &counts {
+            let Some(logit) = logits.get_mut(token as usize) else { continue };
+            if self.repetition_penalty != 1.0 {
+                *logit = if *logit > 0.0 { *logit / self.repetition_penalty } else { *logit * self.repetition_penalty };
+            }
+            *logit -= self.frequency_penalty * count as f32 + self.presence_penalty;
+        }
+
+        for token in banned_ngram_tokens(generated, self.no_repeat_ngram_size) {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+impl LogitsProcessor for PenaltyParams {
+    fn process(&self, generated: &[u32], logits: &mut [f32]) {
+        self.apply(ArrayViewMut1::from(logits), generated);
+    }
+}
+
+/// Tokens that would complete an n-gram already present in `generated`
+fn banned_ngram_tokens(generated: &[u32], ngram_size: usize) -> Vec<u32> {
+    if ngram_size == 0 || generated.len() < ngram_size {
+        return Vec::new();
+    }
+    let prefix = &generated[generated.len() - (ngram_size - 1)..];
+    generated.windows(ngram_size)
+        .filter(|window| &window[..ngram_size - 1] == prefix)
+        .map(|window| window[ngram_size - 1])
+        .collect()
+}
+
+/// Turn raw logits into the candidate distribution to sample from
+///
+/// Runs the warpers of `SamplingParams::warpers` and returns the remaining
+/// `(token_id, probability)` pairs sorted by descending probability. The list is
+/// empty only when every logit has been masked out (NaN or negative infinity).
+pub fn candidate_distribution(logits: &ArrayView1<f32>, params: &SamplingParams) -> Vec<(usize, f32)> {
+    let mut logits = logits.to_vec();
+    let ranked = ranked_indices(&logits);
+    if ranked.is_empty() {
+        return Vec::new();
+    }
+
+    // Temperature 0 collapses the distribution onto the argmax
+    if params.is_greedy() {
+        return vec![(ranked[0], 1.0)];
+    }
+
+    for warper in params.warpers() {
+        warper.process(&[], &mut logits);
+    }
+
+    let probabilities = softmax(&logits);
+    ranked_indices(&logits).into_iter()
+        .map(|idx| (idx, probabilities[idx]))
+        .collect()
+}
+
+/// Draw one token id from a distribution produced by `candidate_distribution`
+pub fn sample_from_distribution<R: Rng + ?Sized>(probabilities: &[(usize, f32)], rng: &mut R) -> Result<usize> {
+    if probabilities.is_empty() {
+        return Err(anyhow::anyhow!("No candidate tokens left after applying logits filters"));
+    }
+    if probabilities.len() == 1 {
+        return Ok(probabilities[0].0);
+    }
+    let weights = WeightedIndex::new(probabilities.iter().map(|(_, prob)| *prob))
+        .map_err(|e| anyhow::anyhow!("Invalid sampling distribution: {}", e))?;
+    Ok(probabilities[weights.sample(rng)].0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_greedy_when_temperature_zero() {
+        let logits = array![0.1, 3.0, 2.0, -1.0];
+        let params = SamplingParams { temperature: 0.0, ..Default::default() };
+        assert_eq!(candidate_distribution(&logits.view(), &params), vec![(1, 1.0)]);
+    }
+
+    #[test]
+    fn test_top_k_then_top_p_then_min_p() {
+        let logits = array![4.0, 3.0, 2.0, 1.0, 0.0];
+        let params = SamplingParams { temperature: 1.0, top_k: 4, top_p: 1.0, min_p: 0.0 };
+        let candidates = candidate_distribution(&logits.view(), &params);
+        assert_eq!(candidates.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+
+        let params = SamplingParams { top_p: 0.9, ..params };
+        let candidates = candidate_distribution(&logits.view(), &params);
+        assert_eq!(candidates.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let params = SamplingParams { min_p: 0.3, ..params };
+        let candidates = candidate_distribution(&logits.view(), &params);
+        assert_eq!(candidates.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 1]);
+        let total: f32 = candidates.iter().map(|(_, p)| p).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_same_seed_same_samples() {
+        let logits = array![1.0, 0.9, 0.8, 0.7, 0.6, 0.5];
+        let params = SamplingParams { temperature: 1.0, top_k: 0, ..Default::default() };
+        let candidates = candidate_distribution(&logits.view(), &params);
+        let draw = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..32).map(|_| sample_from_distribution(&candidates, &mut rng).unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(draw(42), draw(42));
+        assert_ne!(draw(42), draw(7));
+    }
+
+    #[test]
+    fn test_penalties_and_no_repeat_ngram() {
+        let mut logits = array![2.0, -1.0, 1.0, 0.5];
+        let penalties = PenaltyParams {
+            repetition_penalty: 2.0,
+            frequency_penalty: 0.1,
+            presence_penalty: 0.2,
+            no_repeat_ngram_size: 2,
+        };
+        // generated: 0 2 0 -> bigram (0, 2) exists, so 2 is banned after 0
+        penalties.apply(logits.view_mut(), &[0, 2, 0]);
+        assert!((logits[0] - (1.0 - 0.2 - 0.2)).abs() < 1e-6);
+        assert_eq!(logits[1], -1.0);
+        assert_eq!(logits[2], f32::NEG_INFINITY);
+        assert_eq!(logits[3], 0.5);
+    }
+
+    #[test]
+    fn test_validate_rejects_out_of_range() {
+        assert!(SamplingParams::default().validate().is_ok());
+        assert!(SamplingParams { top_p: 0.0, ..Default::default() }.validate().is_err());
+        assert!(SamplingParams { min_p: 1.5, ..Default::default() }.validate().is_err());
+        assert!(SamplingParams { temperature: -1.0, ..Default::default() }.validate().is_err());
+    }
+}
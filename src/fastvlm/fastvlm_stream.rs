@@ -0,0 +1,203 @@
+use anyhow::Result;
+use std::ops::ControlFlow;
+use std::time::Instant;
+
+use super::fastvlm::{FastVLM, FastVLMAnalysisResult};
+use super::fastvlm_generation::{
+    find_stop_sequence, DecodingStrategy, FastVLMUsage, GenerationOutput, GenerationParams, GenerationState,
+    PromptInput,
+};
+
+/// 流式分析产出的事件
+#[derive(Debug, Clone)]
+pub enum FastVLMStreamEvent {
+    /// 新生成的一段文本，保证是完整的 UTF-8 字符
+    Delta(String),
+    /// 生成结束，携带完整的分析结果
+    Done(FastVLMAnalysisResult),
+}
+
+/// Turns the growing decoded text into UTF-8 safe deltas
+///
+/// A delta never ends in the replacement character of an unfinished multi-byte
+/// sequence (byte-fallback tokens), never contains a possible prefix of a stop
+/// sequence and never contains whitespace that the final `trim()` removes, so the
+/// concatenated deltas equal the final text. Text that loop detection may still
+/// remove is never pushed, see `GenerationState::streamable_tokens`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TextDeltaDecoder {
+    emitted: usize,
+}
+
+impl TextDeltaDecoder {
+    /// `text` is the full text decoded so far, `finished` flushes everything that is held back
+    pub fn push(&mut self, text: &str, stop_sequences: &[String], finished: bool) -> Option<String> {
+        let end = match find_stop_sequence(text, stop_sequences) {
+            Some(stop_pos) => stop_pos,
+            None if finished => text.len(),
+            None => text.len() - partial_stop_len(text, stop_sequences),
+        };
+        let mut visible = &text[..end];
+        if !finished {
+            visible = visible.trim_end_matches('\u{FFFD}');
+        }
+        let visible = visible.trim();
+
+        if visible.len() <= self.emitted || !visible.is_char_boundary(self.emitted) {
+            return None;
+        }
+        let delta = visible[self.emitted..].to_string();
+        self.emitted = visible.len();
+        Some(delta)
+    }
+}
+
+/// Length of the longest suffix of `text` that could still grow into a stop sequence
+fn partial_stop_len(text: &str, stop_sequences: &[String]) -> usize {
+    stop_sequences.iter()
+        .filter_map(|stop| {
+            (1..stop.len()).rev()
+                .filter(|&len| stop.is_char_boundary(len))
+                .find(|&len| text.ends_with(&stop[..len]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Drives one analysis a token per `next()` call, used behind `futures_util::stream::iter`
+///
+/// Every `next()` runs the decoder on the calling thread, so polling the stream blocks
+/// the executor for one decoder pass, or the whole beam search on the first poll.
+pub(crate) struct AnalysisStream<'a> {
+    model: &'a mut FastVLM,
+    start_time: Instant,
+    usage: FastVLMUsage,
+    phase: StreamPhase,
+}
+
+enum StreamPhase {
+    /// Beam search runs to completion on the first poll
+    Blocking(PromptInput, GenerationParams),
+    Sampling(GenerationState),
+    Finished(GenerationOutput),
+    Done,
+}
+
+impl<'a> AnalysisStream<'a> {
+    pub fn new(model: &'a mut FastVLM, start_time: Instant, usage: FastVLMUsage, prompt: PromptInput, params: GenerationParams) -> Self {
+        let phase = match params.strategy {
+            DecodingStrategy::Beam { .. } => StreamPhase::Blocking(prompt, params),
+            DecodingStrategy::Sample | DecodingStrategy::Greedy => {
+                StreamPhase::Sampling(model.start_generation(prompt, params))
+            },
+        };
+        Self { model, start_time, usage, phase }
+    }
+}
+
+impl Iterator for AnalysisStream<'_> {
+    type Item = Result<FastVLMStreamEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match std::mem::replace(&mut self.phase, StreamPhase::Done) {
+                StreamPhase::Done => return None,
+                StreamPhase::Finished(output) => {
+                    let result = self.model.finish_analysis(self.start_time, self.usage, vec![output]);
+                    return Some(Ok(FastVLMStreamEvent::Done(result)));
+                },
+                StreamPhase::Blocking(prompt, params) => {
+                    let output = match self.model.generate_with_decoder(prompt, params, &mut |_| ControlFlow::Continue(())) {
+                        Ok(output) => output,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    let text = output.text.clone();
+                    self.phase = StreamPhase::Finished(output);
+                    if !text.is_empty() {
+                        return Some(Ok(FastVLMStreamEvent::Delta(text)));
+                    }
+                },
+                StreamPhase::Sampling(mut state) => {
+                    if state.is_finished() {
+                        match self.model.finish_generation(state) {
+                            Ok(output) => self.phase = StreamPhase::Finished(output),
+                            Err(e) => return Some(Err(e)),
+                        }
+                        continue;
+                    }
+                    match self.model.step_generation(&mut state) {
+                        Ok(delta) => {
+                            self.phase = StreamPhase::Sampling(state);
+                            if let Some(delta) = delta {
+                                return Some(Ok(FastVLMStreamEvent::Delta(delta)));
+                            }
+                        },
+                        Err(e) => return Some(Err(e)),
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastvlm::fastvlm::{FastVLMConfig, FastVLMRequestOptions};
+    use crate::fastvlm::fastvlm_generation::{FinishReason, KvCache, LoopDetection};
+    use ndarray::Array3;
+
+    fn collect(texts: &[&str], stops: &[String]) -> String {
+        let mut decoder = TextDeltaDecoder::default();
+        let mut out = String::new();
+        for (i, text) in texts.iter().enumerate() {
+            if let Some(delta) = decoder.push(text, stops, i + 1 == texts.len()) {
+                out.push_str(&delta);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_holds_back_unfinished_utf8() {
+        let mut decoder = TextDeltaDecoder::default();
+        assert_eq!(decoder.push("一只\u{FFFD}", &[], false), Some("一只".to_string()));
+        assert_eq!(decoder.push("一只\u{FFFD}\u{FFFD}", &[], false), None);
+        assert_eq!(decoder.push("一只猫", &[], false), Some("猫".to_string()));
+    }
+
+    #[test]
+    fn test_deltas_concatenate_to_trimmed_text() {
+        let stops = vec!["\n\n".to_string()];
+        let texts = [" A", " A red", " A red car\n", " A red car\n\nIt"];
+        assert_eq!(collect(&texts, &stops), "A red car");
+        assert_eq!(collect(&[" A", " A cat "], &[]), "A cat");
+    }
+
+    #[test]
+    fn test_detected_loop_is_never_streamed() {
+        let options = FastVLMRequestOptions {
+            loop_detection: Some(LoopDetection { max_ngram_size: 2, min_repeats: 3 }),
+            ..Default::default()
+        };
+        let params = FastVLMConfig::default().generation_params(&options).unwrap();
+        let mut state = GenerationState::new(Array3::zeros((1, 1, 1)), params, KvCache::empty(1, 1, 1));
+        let decode = |tokens: &[u32]| tokens.iter().map(|token| format!(" w{token}")).collect::<String>();
+
+        // Same bookkeeping as `FastVLM::step_generation`, with a fixed token sequence
+        let mut streamed = String::new();
+        for token in [1, 2, 3, 2, 3, 2, 3, 4] {
+            state.push_token(token, None);
+            let text = decode(state.streamable_tokens());
+            if let Some(delta) = state.deltas.push(&text, &[], state.is_finished()) {
+                streamed.push_str(&delta);
+            }
+            if state.is_finished() {
+                break;
+            }
+        }
+        assert_eq!(state.finish_reason, Some(FinishReason::Repetition));
+        assert_eq!(state.tokens, vec![1, 2, 3]);
+        assert_eq!(streamed, "w1 w2 w3");
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+
+use super::fastvlm::{FastVLMAnalysisResult, FastVLMConfig, FastVLMRequestOptions};
+use super::fastvlm_constraint::FastVLMConstraint;
+
+/// Smallest response budget of `analyze_as` when the request does not set one,
+/// JSON answers rarely fit the default `max_response_length`
+pub const STRUCTURED_MIN_RESPONSE_LENGTH: usize = 256;
+
+/// Value extracted by `analyze_as`, together with the analysis it was parsed from
+#[derive(Debug, Clone)]
+pub struct FastVLMTypedResult<T> {
+    pub value: T,
+    /// The analysis whose text was successfully parsed
+    pub analysis: FastVLMAnalysisResult,
+    /// Number of generations needed, `1` when the first answer parsed
+    pub attempts: usize,
+}
+
+/// JSON schema of `T`, pretty printed
+pub(crate) fn schema_json<T: JsonSchema>() -> Result<String> {
+    let schema = schemars::schema_for!(T);
+    Ok(serde_json::to_string_pretty(&schema)?)
+}
+
+/// Options of an `analyze_as` request: a response budget large enough for JSON and,
+/// unless a constraint is set, a constraint to one bracketed JSON value
+pub(crate) fn structured_options(
+    config: &FastVLMConfig,
+    options: &FastVLMRequestOptions,
+    schema: &str,
+) -> Result<FastVLMRequestOptions> {
+    let mut options = options.clone();
+    options.max_response_length.get_or_insert(config.max_response_length.max(STRUCTURED_MIN_RESPONSE_LENGTH));
+    if options.constraint.is_none() && config.constraint.is_none() {
+        options.constraint = json_constraint(schema)?;
+    }
+    Ok(options)
+}
+
+/// Constraint to an object or array answer, following the root type of `schema`
+///
+/// Only the outer brackets are enforced, the content is checked when parsing.
+fn json_constraint(schema: &str) -> Result<Option<FastVLMConstraint>> {
+    let schema: serde_json::Value = serde_json::from_str(schema)?;
+    let pattern = match schema.get("type").and_then(|root| root.as_str()) {
+        Some("object") => r"(?s)\s*\{.*\}\s*",
+        Some("array") => r"(?s)\s*\[.*\]\s*",
+        _ => return Ok(None),
+    };
+    Ok(Some(FastVLMConstraint::Regex(pattern.to_string())))
+}
+
+/// Prompt asking for a JSON answer that follows `schema`
+pub(crate) fn schema_prompt(prompt: &str, schema: &str) -> String {
+    format!(
+        "{}\n\nRespond only with a JSON value that matches this JSON schema, without any explanation:\n{}",
+        prompt, schema
+    )
+}
+
+/// Prompt for another attempt after `previous` failed to parse
+pub(crate) fn repair_prompt(prompt: &str, schema: &str, previous: &str, error: &anyhow::Error) -> String {
+    format!(
+        "{}\n\nYour previous answer was not valid JSON for the schema ({}):\n{}\n\nRespond again, only with corrected JSON.",
+        schema_prompt(prompt, schema), error, previous
+    )
+}
+
+/// Parse the JSON value contained in a model answer
+///
+/// Tolerates Markdown code fences, text around the JSON value and trailing
+/// commas before `}` or `]`.
+pub(crate) fn parse_json_answer<T: DeserializeOwned>(text: &str) -> Result<T> {
+    let json = extract_json(text).ok_or_else(|| anyhow::anyhow!("No JSON value found in answer"))?;
+    match serde_json::from_str(json) {
+        Ok(value) => Ok(value),
+        Err(first_error) => serde_json::from_str(&remove_trailing_commas(json))
+            .map_err(|_| anyhow::anyhow!("Invalid JSON: {}", first_error)),
+    }
+}
+
+/// The span from the first `{` or `[` to the last matching closing bracket
+fn extract_json(text: &str) -> Option<&str> {
+    let start = text.find(['{', '['])?;
+    let close = if text[start..].starts_with('{') { '}' } else { ']' };
+    let end = text.rfind(close)?;
+    (end > start).then(|| &text[start..=end])
+}
+
+/// Drop commas directly followed (ignoring whitespace) by `}` or `]`, outside of strings
+fn remove_trailing_commas(json: &str) -> String {
+    let chars: Vec<char> = json.chars().collect();
+    let mut repaired = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, &c) in chars.iter().enumerate() {
+        if in_string {
+            escaped = !escaped && c == '\\';
+            in_string = escaped || c != '"';
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' {
+            let next = chars[i + 1..].iter().find(|c| !c.is_whitespace());
+            if matches!(next, Some('}') | Some(']')) {
+                continue;
+            }
+        }
+        repaired.push(c);
+    }
+    repaired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+    struct Scene {
+        indoor: bool,
+        objects: Vec<String>,
+    }
+
+    #[test]
+    fn test_parse_fenced_answer_with_trailing_commas() {
+        let answer = "Here you go:\n```json\n{\"indoor\": true, \"objects\": [\"desk\", \"lamp\",],}\n```";
+        let scene: Scene = parse_json_answer(answer).unwrap();
+        assert_eq!(scene, Scene { indoor: true, objects: vec!["desk".to_string(), "lamp".to_string()] });
+
+        assert!(parse_json_answer::<Scene>("a desk and a lamp").is_err());
+        assert!(parse_json_answer::<Scene>("{\"indoor\": \"yes\"}").is_err());
+    }
+
+    #[test]
+    fn test_trailing_commas_inside_strings_are_kept() {
+        assert_eq!(remove_trailing_commas(r#"{"a": ",}", "b": [1,],}"#), r#"{"a": ",}", "b": [1]}"#);
+    }
+
+    #[test]
+    fn test_structured_options() {
+        let schema = schema_json::<Scene>().unwrap();
+        let config = FastVLMConfig::default();
+        let options = structured_options(&config, &FastVLMRequestOptions::default(), &schema).unwrap();
+        assert_eq!(options.max_response_length, Some(STRUCTURED_MIN_RESPONSE_LENGTH));
+        let Some(FastVLMConstraint::Regex(pattern)) = &options.constraint else { panic!("no JSON constraint") };
+        let regex = regex_automata::meta::Regex::new(&format!("^(?:{})$", pattern)).unwrap();
+        assert!(regex.is_match(" {\"indoor\": true,\n \"objects\": []}\n"));
+        assert!(!regex.is_match("Here you go: {\"indoor\": true}"));
+        assert!(!regex.is_match("```json\n{}\n```"));
+
+        // Explicit budgets and constraints are kept, other root types are not constrained
+        let choice = FastVLMConstraint::Choice(vec!["{}".to_string()]);
+        let explicit = FastVLMRequestOptions { max_response_length: Some(40), constraint: Some(choice.clone()), ..Default::default() };
+        let options = structured_options(&config, &explicit, &schema).unwrap();
+        assert_eq!((options.max_response_length, options.constraint), (Some(40), Some(choice)));
+        let long = FastVLMConfig { max_response_length: 1000, ..Default::default() };
+        let options = structured_options(&long, &FastVLMRequestOptions::default(), &schema_json::<bool>().unwrap()).unwrap();
+        assert_eq!((options.max_response_length, options.constraint), (Some(1000), None));
+    }
+
+    #[test]
+    fn test_schema_prompt_contains_fields() {
+        let schema = schema_json::<Scene>().unwrap();
+        let prompt = schema_prompt("Describe the scene.", &schema);
+        assert!(prompt.starts_with("Describe the scene."));
+        assert!(prompt.contains("\"objects\""));
+    }
+}
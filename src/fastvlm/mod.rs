@@ -1,4 +1,30 @@
 pub mod fastvlm_image_process;
 pub mod fastvlm;
+pub mod fastvlm_generation;
+pub mod fastvlm_sampling;
+pub mod fastvlm_stream;
+pub mod fastvlm_classify;
+pub mod fastvlm_logits;
+pub mod fastvlm_constraint;
+pub mod fastvlm_structured;
+pub mod fastvlm_decoder_spec;
+pub mod fastvlm_model_config;
+pub mod fastvlm_chat_template;
+pub mod fastvlm_chat;
+pub mod fastvlm_cache;
+pub mod fastvlm_context;
 
-pub use fastvlm::{FastVLM, FastVLMConfig, FastVLMAnalysisResult};
\ No newline at end of file
+pub use fastvlm::{FastVLM, FastVLMConfig, FastVLMAnalysisResult, FastVLMRequestOptions, FastVLMContentPart};
+pub use fastvlm_generation::{DecodingStrategy, FastVLMCandidate, FastVLMLogprobs, FastVLMUsage, FinishReason, LoopDetection, TokenAlternative, TokenLogprob};
+pub use fastvlm_stream::FastVLMStreamEvent;
+pub use fastvlm_classify::{FastVLMClassification, FastVLMLabelScore};
+pub use fastvlm_constraint::FastVLMConstraint;
+pub use fastvlm_structured::{FastVLMTypedResult, STRUCTURED_MIN_RESPONSE_LENGTH};
+pub use fastvlm_chat_template::{FastVLMMessage, DEFAULT_SYSTEM_PROMPT};
+pub use fastvlm_chat::ChatSession;
+pub use fastvlm_cache::FastVLMCacheStats;
+pub use fastvlm_context::{ContextOverflow, FastVLMTruncation};
+pub use fastvlm_logits::{
+    LogitBiasProcessor, LogitsProcessor, MinPLogitsWarper, StoppingCriteria, SuppressTokensLogitsProcessor,
+    TemperatureLogitsWarper, TopKLogitsWarper, TopPLogitsWarper,
+};
\ No newline at end of file
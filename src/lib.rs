@@ -35,9 +35,17 @@
 pub mod fastvlm;
 pub mod download;
 
-pub use fastvlm::{FastVLM, FastVLMConfig, FastVLMAnalysisResult};
-pub use download::{download_fastvlm_models, get_default_model_dir};
+pub use fastvlm::{FastVLM, FastVLMConfig, FastVLMAnalysisResult, FastVLMRequestOptions, FastVLMContentPart, DecodingStrategy, FinishReason, LoopDetection, FastVLMStreamEvent, FastVLMLogprobs, FastVLMUsage, FastVLMCandidate, TokenLogprob, TokenAlternative, FastVLMClassification, FastVLMLabelScore};
+pub use fastvlm::{LogitsProcessor, StoppingCriteria, LogitBiasProcessor, SuppressTokensLogitsProcessor, TemperatureLogitsWarper, TopKLogitsWarper, TopPLogitsWarper, MinPLogitsWarper, FastVLMConstraint, FastVLMTypedResult, STRUCTURED_MIN_RESPONSE_LENGTH, FastVLMMessage, DEFAULT_SYSTEM_PROMPT, ChatSession, FastVLMCacheStats, ContextOverflow, FastVLMTruncation};
+pub use download::{
+    download_fastvlm_models, get_default_model_dir, FastVLMComponent, FastVLMDtype, FastVLMManifest,
+    FastVLMModelSize, FastVLMVariant,
+};
 use anyhow::Result;
+use futures_util::Stream;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use std::ops::ControlFlow;
 
 /// FastVLM 库的主要接口
 pub struct FastVLMClient {
@@ -69,11 +77,19 @@ impl FastVLMClient {
             get_default_model_dir().to_string_lossy().to_string()
         };
 
-        // 检查模型文件是否存在
+        // 检查模型目录清单，缺少模型文件或指定了其他变体时下载；
+        // 没有清单但文件齐全的目录（例如旧版本下载的）直接按已有文件写入清单
         let model_dir = std::path::Path::new(&path);
-        if !model_dir.exists() {
+        let needs_download = match FastVLMManifest::load(model_dir)? {
+            Some(manifest) => {
+                config.model_variant.is_some_and(|variant| variant != manifest.variant)
+                    || !manifest.files_present(model_dir)
+            },
+            None => FastVLMManifest::adopt_existing(model_dir, config.model_variant.unwrap_or_default())?.is_none(),
+        };
+        if needs_download {
             tracing::info!("模型文件不存在，开始下载...");
-            download_fastvlm_models(model_dir).await?;
+            download_fastvlm_models(model_dir, config.model_variant.unwrap_or_default()).await?;
         }
 
         // 初始化模型
@@ -101,9 +117,31 @@ impl FastVLMClient {
         width: u32,
         height: u32,
         prompt: Option<String>,
+    ) -> Result<FastVLMAnalysisResult> {
+        self.analyze_image_with_options(image_data, width, height, prompt, &FastVLMRequestOptions::default()).await
+    }
+
+    /// 分析图片，并使用单次请求的选项覆盖模型配置
+    /// 
+    /// # 参数
+    /// * `image_data` - 图片的RGBA字节数据
+    /// * `width` - 图片宽度
+    /// * `height` - 图片高度
+    /// * `prompt` - 可选的提示文本
+    /// * `options` - 本次请求的选项（温度、top-k 等）
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMAnalysisResult>` - 分析结果
+    pub async fn analyze_image_with_options(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
     ) -> Result<FastVLMAnalysisResult> {
         if let Some(ref mut model) = self.model {
-            model.analyze_frame(image_data, width, height, prompt).await
+            model.analyze_frame_with_options(image_data, width, height, prompt, options).await
         } else {
             Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
         }
@@ -122,19 +160,441 @@ impl FastVLMClient {
         image_path: &str,
         prompt: Option<String>,
     ) -> Result<FastVLMAnalysisResult> {
-        // 加载图片
-        let img = image::open(image_path)?;
-        let (width, height) = (img.width(), img.height());
-        
-        // 转换为RGBA字节
-        let rgba_img = img.to_rgba8();
-        let mut image_data = Vec::with_capacity((width * height * 4) as usize);
-        
-        for pixel in rgba_img.pixels() {
-            image_data.extend_from_slice(&[pixel[0], pixel[1], pixel[2], pixel[3]]);
+        self.analyze_image_file_with_options(image_path, prompt, &FastVLMRequestOptions::default()).await
+    }
+
+    /// 从文件路径分析图片，并使用单次请求的选项覆盖模型配置
+    /// 
+    /// # 参数
+    /// * `image_path` - 图片文件路径
+    /// * `prompt` - 可选的提示文本
+    /// * `options` - 本次请求的选项
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMAnalysisResult>` - 分析结果
+    pub async fn analyze_image_file_with_options(
+        &mut self,
+        image_path: &str,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        let (image_data, width, height) = load_rgba_image(image_path)?;
+        self.analyze_image_with_options(image_data, width, height, prompt, options).await
+    }
+
+    /// 对同一张图片提出多个问题，每个问题返回一个结果
+    /// 
+    /// 图片只编码一次，系统提示词和图片部分只预填充一次，之后每个问题只处理自己的 token。
+    /// 
+    /// # 参数
+    /// * `image_data` - 图片的RGBA字节数据
+    /// * `width` - 图片宽度
+    /// * `height` - 图片高度
+    /// * `prompts` - 问题列表
+    /// * `options` - 本次请求的选项，对每个问题都生效
+    /// 
+    /// # 返回
+    /// * `Result<Vec<FastVLMAnalysisResult>>` - 与 `prompts` 一一对应的分析结果
+    pub async fn analyze_image_multi<S: AsRef<str>>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompts: &[S],
+        options: &FastVLMRequestOptions,
+    ) -> Result<Vec<FastVLMAnalysisResult>> {
+        if let Some(ref mut model) = self.model {
+            model.analyze_frame_multi(image_data, width, height, prompts, options).await
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
         }
+    }
 
-        self.analyze_image(image_data, width, height, prompt).await
+    /// 从文件路径读取图片并提出多个问题
+    /// 
+    /// # 参数
+    /// * `image_path` - 图片文件路径
+    /// * `prompts` - 问题列表
+    /// * `options` - 本次请求的选项
+    /// 
+    /// # 返回
+    /// * `Result<Vec<FastVLMAnalysisResult>>` - 与 `prompts` 一一对应的分析结果
+    pub async fn analyze_image_file_multi<S: AsRef<str>>(
+        &mut self,
+        image_path: &str,
+        prompts: &[S],
+        options: &FastVLMRequestOptions,
+    ) -> Result<Vec<FastVLMAnalysisResult>> {
+        let (image_data, width, height) = load_rgba_image(image_path)?;
+        self.analyze_image_multi(image_data, width, height, prompts, options).await
+    }
+
+    /// 分析由文本和多张图片交错组成的提示词
+    /// 
+    /// # 参数
+    /// * `parts` - 按顺序排列的文本和图片，图片可用 `FastVLMContentPart::image_file` 从文件读取
+    /// * `options` - 本次请求的选项
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMAnalysisResult>` - 分析结果，`usage.image_tokens` 为所有图片的特征数之和
+    pub async fn analyze_parts(
+        &mut self,
+        parts: Vec<FastVLMContentPart>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        if let Some(ref mut model) = self.model {
+            model.analyze_parts(parts, options).await
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 分析图片，并在生成过程中逐段回调新生成的文本
+    /// 
+    /// # 参数
+    /// * `image_data` - 图片的RGBA字节数据
+    /// * `width` - 图片宽度
+    /// * `height` - 图片高度
+    /// * `prompt` - 可选的提示文本
+    /// * `options` - 本次请求的选项
+    /// * `on_delta` - 每段新文本的回调，返回 `ControlFlow::Break(())` 可提前停止生成
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMAnalysisResult>` - 完整的分析结果
+    pub async fn analyze_image_with_callback<F>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+        on_delta: F,
+    ) -> Result<FastVLMAnalysisResult>
+    where
+        F: FnMut(&str) -> ControlFlow<()>,
+    {
+        if let Some(ref mut model) = self.model {
+            model.analyze_frame_with_callback(image_data, width, height, prompt, options, on_delta).await
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 以 `Stream` 的形式分析图片
+    /// 
+    /// # 参数
+    /// * `image_data` - 图片的RGBA字节数据
+    /// * `width` - 图片宽度
+    /// * `height` - 图片高度
+    /// * `prompt` - 可选的提示文本
+    /// * `options` - 本次请求的选项
+    /// 
+    /// # 返回
+    /// * `Result<impl Stream>` - 依次产出 `FastVLMStreamEvent::Delta`，最后产出 `FastVLMStreamEvent::Done`
+    /// 
+    /// # 阻塞
+    /// 每次轮询 Stream 都会在当前线程同步运行一次解码器前向，期间不会让出执行器。
+    /// 在异步服务中使用时，请在 `spawn_blocking` 或专用线程中消费 Stream。
+    pub async fn analyze_image_stream(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<impl Stream<Item = Result<FastVLMStreamEvent>> + '_> {
+        let model = self.model.as_mut()
+            .ok_or_else(|| anyhow::anyhow!("模型未初始化，请先调用 initialize()"))?;
+        model.analyze_frame_stream(image_data, width, height, prompt, options)
+    }
+
+    /// 从文件路径以 `Stream` 的形式分析图片
+    /// 
+    /// # 参数
+    /// * `image_path` - 图片文件路径
+    /// * `prompt` - 可选的提示文本
+    /// * `options` - 本次请求的选项
+    /// 
+    /// # 返回
+    /// * `Result<impl Stream>` - 依次产出 `FastVLMStreamEvent::Delta`，最后产出 `FastVLMStreamEvent::Done`
+    /// 
+    /// # 阻塞
+    /// 每次轮询 Stream 都会在当前线程同步运行一次解码器前向，期间不会让出执行器。
+    /// 在异步服务中使用时，请在 `spawn_blocking` 或专用线程中消费 Stream。
+    pub async fn analyze_image_file_stream(
+        &mut self,
+        image_path: &str,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<impl Stream<Item = Result<FastVLMStreamEvent>> + '_> {
+        let (image_data, width, height) = load_rgba_image(image_path)?;
+        self.analyze_image_stream(image_data, width, height, prompt, options).await
+    }
+
+    /// 分析图片并把回答解析为结构化类型 `T`
+    /// 
+    /// # 参数
+    /// * `image_data` - 图片的RGBA字节数据
+    /// * `width` - 图片宽度
+    /// * `height` - 图片高度
+    /// * `prompt` - 可选的提示文本，会自动附加 `T` 的 JSON Schema
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMTypedResult<T>>` - 解析得到的值和对应的分析结果
+    pub async fn analyze_as<T: DeserializeOwned + JsonSchema>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+    ) -> Result<FastVLMTypedResult<T>> {
+        self.analyze_as_with_options(image_data, width, height, prompt, &FastVLMRequestOptions::default()).await
+    }
+
+    /// 分析图片并把回答解析为结构化类型 `T`，并使用单次请求的选项覆盖模型配置
+    /// 
+    /// # 参数
+    /// * `image_data` - 图片的RGBA字节数据
+    /// * `width` - 图片宽度
+    /// * `height` - 图片高度
+    /// * `prompt` - 可选的提示文本，会自动附加 `T` 的 JSON Schema
+    /// * `options` - 本次请求的选项，`max_parse_retries` 控制解析失败时的重试次数
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMTypedResult<T>>` - 解析得到的值和对应的分析结果
+    pub async fn analyze_as_with_options<T: DeserializeOwned + JsonSchema>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMTypedResult<T>> {
+        if let Some(ref mut model) = self.model {
+            model.analyze_as(image_data, width, height, prompt, options).await
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 从文件路径分析图片并把回答解析为结构化类型 `T`
+    /// 
+    /// # 参数
+    /// * `image_path` - 图片文件路径
+    /// * `prompt` - 可选的提示文本，会自动附加 `T` 的 JSON Schema
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMTypedResult<T>>` - 解析得到的值和对应的分析结果
+    pub async fn analyze_file_as<T: DeserializeOwned + JsonSchema>(
+        &mut self,
+        image_path: &str,
+        prompt: Option<String>,
+    ) -> Result<FastVLMTypedResult<T>> {
+        let (image_data, width, height) = load_rgba_image(image_path)?;
+        self.analyze_as(image_data, width, height, prompt).await
+    }
+
+    /// 零样本分类：计算每个候选标签作为回答的概率
+    /// 
+    /// # 参数
+    /// * `image_data` - 图片的RGBA字节数据
+    /// * `width` - 图片宽度
+    /// * `height` - 图片高度
+    /// * `prompt` - 可选的提示文本，例如 "这张照片是在室内还是室外拍摄的？"
+    /// * `labels` - 候选标签
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMClassification>` - 每个标签的对数似然和归一化概率
+    /// 
+    /// 标签得分是各 token 对数概率之和，token 较多的标签天然得分更低，见 `FastVLM::classify_sync`
+    pub async fn classify_image<S: AsRef<str>>(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        prompt: Option<String>,
+        labels: &[S],
+    ) -> Result<FastVLMClassification> {
+        if let Some(ref mut model) = self.model {
+            model.classify(image_data, width, height, prompt, labels).await
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 从文件路径对图片进行零样本分类
+    /// 
+    /// # 参数
+    /// * `image_path` - 图片文件路径
+    /// * `prompt` - 可选的提示文本
+    /// * `labels` - 候选标签
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMClassification>` - 每个标签的对数似然和归一化概率
+    pub async fn classify_image_file<S: AsRef<str>>(
+        &mut self,
+        image_path: &str,
+        prompt: Option<String>,
+        labels: &[S],
+    ) -> Result<FastVLMClassification> {
+        let (image_data, width, height) = load_rgba_image(image_path)?;
+        self.classify_image(image_data, width, height, prompt, labels).await
+    }
+
+    /// 不带图片的纯文本生成，使用同一个解码器完成汇总、改写等文本任务
+    /// 
+    /// # 参数
+    /// * `messages` - 对话消息，不以 system 消息开头时使用配置中的系统提示词
+    /// * `options` - 本次请求的选项
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMAnalysisResult>` - 生成结果，`usage.image_tokens` 为 0
+    pub async fn generate_text(
+        &mut self,
+        messages: &[FastVLMMessage],
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        if let Some(ref mut model) = self.model {
+            model.generate_text(messages, options).await
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 以 `Stream` 的形式进行纯文本生成
+    /// 
+    /// # 参数
+    /// * `messages` - 对话消息
+    /// * `options` - 本次请求的选项
+    /// 
+    /// # 返回
+    /// * `Result<impl Stream>` - 依次产出 `FastVLMStreamEvent::Delta`，最后产出 `FastVLMStreamEvent::Done`
+    /// 
+    /// # 阻塞
+    /// 每次轮询 Stream 都会在当前线程同步运行一次解码器前向，期间不会让出执行器。
+    /// 在异步服务中使用时，请在 `spawn_blocking` 或专用线程中消费 Stream。
+    pub async fn generate_text_stream(
+        &mut self,
+        messages: &[FastVLMMessage],
+        options: &FastVLMRequestOptions,
+    ) -> Result<impl Stream<Item = Result<FastVLMStreamEvent>> + '_> {
+        let model = self.model.as_mut()
+            .ok_or_else(|| anyhow::anyhow!("模型未初始化，请先调用 initialize()"))?;
+        model.generate_text_stream(messages, options)
+    }
+
+    /// 开始一段关于图片的多轮对话，图片只编码一次
+    /// 
+    /// # 参数
+    /// * `image_data` - 图片的RGBA字节数据
+    /// * `width` - 图片宽度
+    /// * `height` - 图片高度
+    /// * `system_prompt` - 可选的系统提示词，None 时使用配置中的系统提示词
+    /// 
+    /// # 返回
+    /// * `Result<ChatSession>` - 对话会话，保存历史消息和 KV 缓存
+    pub async fn start_chat(
+        &mut self,
+        image_data: Vec<u8>,
+        width: u32,
+        height: u32,
+        system_prompt: Option<String>,
+    ) -> Result<ChatSession> {
+        if let Some(ref mut model) = self.model {
+            model.start_chat(image_data, width, height, system_prompt).await
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 从文件路径开始多轮对话
+    /// 
+    /// # 参数
+    /// * `image_path` - 图片文件路径
+    /// * `system_prompt` - 可选的系统提示词
+    /// 
+    /// # 返回
+    /// * `Result<ChatSession>` - 对话会话
+    pub async fn start_chat_file(&mut self, image_path: &str, system_prompt: Option<String>) -> Result<ChatSession> {
+        let (image_data, width, height) = load_rgba_image(image_path)?;
+        self.start_chat(image_data, width, height, system_prompt).await
+    }
+
+    /// 在对话中发送一条消息，只预填充上一轮之后新增的 token
+    /// 
+    /// # 参数
+    /// * `session` - `start_chat` 返回的会话
+    /// * `message` - 用户消息
+    /// * `options` - 单次请求的选项
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMAnalysisResult>` - 本轮回答，同时追加到会话历史
+    pub async fn chat(
+        &mut self,
+        session: &mut ChatSession,
+        message: &str,
+        options: &FastVLMRequestOptions,
+    ) -> Result<FastVLMAnalysisResult> {
+        if let Some(ref mut model) = self.model {
+            model.chat(session, message, options).await
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 把对话会话保存到文件，之后可以在其他进程中恢复
+    /// 
+    /// # 参数
+    /// * `session` - 要保存的会话
+    /// * `path` - 文件路径
+    /// 
+    /// # 返回
+    /// * `Result<()>` - 保存结果
+    pub fn save_chat_session(&self, session: &ChatSession, path: &str) -> Result<()> {
+        if let Some(ref model) = self.model {
+            model.save_chat_session(session, std::path::Path::new(path))
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 从文件恢复对话会话，跳过图片编码和预填充
+    /// 
+    /// # 参数
+    /// * `path` - `save_chat_session` 保存的文件路径
+    /// 
+    /// # 返回
+    /// * `Result<ChatSession>` - 恢复的会话，模型变体或权重不同时返回错误
+    pub fn load_chat_session(&self, path: &str) -> Result<ChatSession> {
+        if let Some(ref model) = self.model {
+            model.load_chat_session(std::path::Path::new(path))
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 视觉特征缓存的统计信息
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMCacheStats>` - 命中次数、未命中次数、条目数和占用字节数
+    pub fn vision_cache_stats(&self) -> Result<FastVLMCacheStats> {
+        if let Some(ref model) = self.model {
+            Ok(model.vision_cache_stats())
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
+    }
+
+    /// 系统提示词和图片前缀缓存的统计信息
+    /// 
+    /// # 返回
+    /// * `Result<FastVLMCacheStats>` - 命中次数、未命中次数、条目数和占用字节数
+    pub fn prefix_cache_stats(&self) -> Result<FastVLMCacheStats> {
+        if let Some(ref model) = self.model {
+            Ok(model.prefix_cache_stats())
+        } else {
+            Err(anyhow::anyhow!("模型未初始化，请先调用 initialize()"))
+        }
     }
 
     /// 检查模型是否已初始化
@@ -163,6 +623,22 @@ impl Drop for FastVLMClient {
     }
 }
 
+/// 加载图片文件并转换为RGBA字节
+fn load_rgba_image(image_path: &str) -> Result<(Vec<u8>, u32, u32)> {
+    let img = image::open(image_path)?;
+    let (width, height) = (img.width(), img.height());
+    
+    // 转换为RGBA字节
+    let rgba_img = img.to_rgba8();
+    let mut image_data = Vec::with_capacity((width * height * 4) as usize);
+    
+    for pixel in rgba_img.pixels() {
+        image_data.extend_from_slice(&[pixel[0], pixel[1], pixel[2], pixel[3]]);
+    }
+
+    Ok((image_data, width, height))
+}
+
 /// 便捷函数：自动下载并初始化模型
 pub async fn create_fastvlm_client(
     model_path: Option<&str>,
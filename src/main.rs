@@ -102,6 +102,7 @@ impl FastVLMApp {
         let config = FastVLMConfig {
             max_response_length: 30,
             default_prompt: "用中文描述这张图片的内容".to_string(),
+            ..Default::default()
         };
         
         // 初始化模型